@@ -1,22 +1,154 @@
 use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
 
 use rust_lisp::model::Value as RValue;
 use serde::{Deserialize, Deserializer};
 
+use crate::criteria::Criteria;
+use crate::i3ipc::{Backlog, WindowChange};
+use crate::lisp;
+
+/// A lisp program which could not be parsed.
 #[derive(Clone, Debug)]
-pub struct Value(Vec<RValue>);
+pub struct SyntaxError {
+    line: usize,
+    column: usize,
+    /// The line containing the error.
+    text: String,
+    msg: String,
+}
+
+impl SyntaxError {
+    fn at(source: &str, offset: usize, msg: &str) -> Self {
+        let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[offset..]
+            .find('\n')
+            .map_or(source.len(), |i| offset + i);
+        Self {
+            line: source[..offset].matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            text: source[line_start..line_end].trim().to_string(),
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}: {}",
+            self.line, self.column, self.msg, self.text
+        )
+    }
+}
+
+/// Check that parentheses and strings of `source` are balanced and return
+/// the offsets at which top level forms start.
+fn scan(source: &str) -> Result<Vec<usize>, SyntaxError> {
+    let mut starts = Vec::new();
+    let mut open = Vec::new();
+    // Inside a top level atom, or after a quote prefixing the next form.
+    let mut in_form = false;
+    let mut chars = source.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if open.is_empty() && !in_form && !c.is_whitespace() && c != ';' && c != ')' {
+            starts.push(i);
+        }
+        match c {
+            ';' => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                in_form = false;
+            }
+            '"' => {
+                let mut escaped = false;
+                let mut closed = false;
+                for (_, c) in chars.by_ref() {
+                    if escaped {
+                        escaped = false;
+                    } else if c == '\\' {
+                        escaped = true;
+                    } else if c == '"' {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err(SyntaxError::at(source, i, "unterminated string"));
+                }
+                in_form = false;
+            }
+            '(' => {
+                open.push(i);
+                in_form = false;
+            }
+            ')' => {
+                if open.pop().is_none() {
+                    return Err(SyntaxError::at(source, i, "unexpected )"));
+                }
+                in_form = false;
+            }
+            '\'' => in_form = true,
+            c if c.is_whitespace() => in_form = false,
+            _ => in_form = true,
+        }
+    }
+    match open.last() {
+        Some(i) => Err(SyntaxError::at(source, *i, "unclosed (")),
+        None => Ok(starts),
+    }
+}
+
+/// Parse a lisp program, locating errors within `source`.
+pub fn parse(source: &str) -> Result<Vec<RValue>, SyntaxError> {
+    let starts = scan(source)?;
+    let mut program = Vec::new();
+    for (i, start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(source.len());
+        for value in rust_lisp::parser::parse(&source[*start..end]) {
+            program.push(value.map_err(|e| SyntaxError::at(source, *start, &e.msg))?);
+        }
+    }
+    Ok(program)
+}
+
+/// A lisp program, parse errors are kept to be reported by
+/// [`Config::validate`].
+#[derive(Clone, Debug)]
+pub struct Value {
+    program: Vec<RValue>,
+    error: Option<SyntaxError>,
+}
 unsafe impl Send for Value {}
 unsafe impl Sync for Value {}
 
+impl Value {
+    /// Why the program is not valid, if it is not.
+    fn check(&self, allow_empty: bool) -> Result<(), String> {
+        match &self.error {
+            Some(e) => Err(e.to_string()),
+            None if self.program.is_empty() && !allow_empty => Err("empty program".to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
 impl Into<Value> for RValue {
     fn into(self) -> Value {
-        Value(vec![self])
+        Value {
+            program: vec![self],
+            error: None,
+        }
     }
 }
 
 impl Into<Vec<RValue>> for Value {
     fn into(self) -> Vec<RValue> {
-        self.0
+        self.program
     }
 }
 
@@ -24,7 +156,7 @@ impl Display for Value {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         let mut s = String::new();
         s.push_str("(begin\n");
-        for i in &self.0 {
+        for i in &self.program {
             s.push_str(&format!("{}\n", i));
         }
         s.push_str(")");
@@ -38,23 +170,69 @@ impl<'de> Deserialize<'de> for Value {
         D: Deserializer<'de>,
     {
         let s: String = Deserialize::deserialize(deserializer)?;
-        let r: Vec<RValue> = rust_lisp::parser::parse(&s)
-            .filter_map(|x| x.ok())
-            .collect();
-        Ok(Value(r))
+        Ok(match parse(&s) {
+            Ok(program) => Value {
+                program,
+                error: None,
+            },
+            Err(e) => Value {
+                program: vec![],
+                error: Some(e),
+            },
+        })
     }
 }
 
+/// The commands of a matched program.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Cmd {
+    /// A command in which `{result}` is replaced.
+    Format(String),
+    /// A lisp program returning a command or a list of commands, the result
+    /// of the match is bound to `result`.
+    Eval { eval: Value },
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Program {
-    #[serde(rename = "match")]
-    pub match_: Value,
-    pub cmd: String,
+    #[serde(rename = "match", default)]
+    pub match_: Option<Value>,
+    #[serde(default)]
+    pub criteria: Option<Criteria>,
+    /// The window event `criteria` applies to.
+    #[serde(default = "Program::default_change")]
+    pub change: WindowChange,
+    #[serde(default)]
+    pub cmd: Option<Cmd>,
+    #[serde(default)]
+    pub run: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl Program {
+    fn default_change() -> WindowChange {
+        WindowChange::New
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tick {
     #[serde(default)]
     pub run: Option<String>,
+    pub tick: String,
+    #[serde(default = "Tick::default_timeout")]
+    pub timeout: u64,
+}
+impl Tick {
+    fn default_timeout() -> u64 {
+        500
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Signal {
     #[serde(default)]
     pub run: Option<String>,
@@ -69,9 +247,12 @@ impl Signal {
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
 pub enum ProgramEntry {
-    Program(Program),
+    // Every field of a program is optional, so the others are tried first.
+    Tick(Tick),
     Signal(Signal),
+    Program(Program),
 }
 
 // Program is only unsafe because Value has dyn Any in it (via Foreign).
@@ -88,6 +269,15 @@ pub struct Config {
     pub cmd: Option<String>,
     #[serde(default = "Config::default_programs")]
     pub programs: Vec<ProgramEntry>,
+    #[serde(default)]
+    pub backlog: Backlog,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub budget: lisp::Budget,
+    /// The directory of the configuration file, if it was read from one.
+    #[serde(skip)]
+    pub directory: Option<PathBuf>,
 }
 // Config is only unsafe because Value has dyn Any in it (via Foreign).
 // if we don't use !Send in Foreign everything is fine.
@@ -97,9 +287,116 @@ impl Config {
         3000
     }
     fn default_init() -> Value {
-        Value(vec![])
+        Value {
+            program: vec![],
+            error: None,
+        }
     }
     fn default_programs() -> Vec<ProgramEntry> {
         vec![]
     }
+
+    /// Report every lisp program that could not be parsed or is empty.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let mut errors = Vec::new();
+        if let Err(e) = self.init.check(true) {
+            errors.push(format!("init: {}", e));
+        }
+        for (i, p) in self.programs.iter().enumerate() {
+            if let ProgramEntry::Program(p) = p {
+                match (&p.match_, &p.criteria) {
+                    (Some(_), Some(_)) => errors.push(format!(
+                        "programs[{}]: match and criteria can not be used together",
+                        i
+                    )),
+                    (None, None) => {
+                        errors.push(format!("programs[{}]: match or criteria is required", i))
+                    }
+                    (Some(m), None) => {
+                        if let Err(e) = m.check(false) {
+                            errors.push(format!("programs[{}].match: {}", i, e));
+                        }
+                    }
+                    (None, Some(c)) => {
+                        if let Err(e) = c.check() {
+                            errors.push(format!("programs[{}].criteria: {}", i, e));
+                        }
+                        if p.cmd.is_none() {
+                            errors.push(format!("programs[{}]: criteria requires cmd", i));
+                        }
+                    }
+                }
+                if let Some(Cmd::Eval { eval }) = &p.cmd {
+                    if let Err(e) = eval.check(false) {
+                        errors.push(format!("programs[{}].cmd.eval: {}", i, e));
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid configuration:\n  {}",
+                errors.join("\n  ")
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syntax_errors_are_located() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+init: |
+  (defun a () 1)
+  (defun b () (a)
+programs:
+- cmd: 'nop'
+  match: '(a))'
+- cmd: 'nop'
+  match: '(== "x)'
+- cmd: 'nop'
+  match: '; nothing'
+- cmd: 'nop'
+  match: '(a)'
+  criteria: '[class="a"]'
+- cmd: 'nop'
+- cmd: 'nop'
+  criteria: '[class="a"'
+- criteria: '[class="a"]'
+- match: '(a)'
+  cmd:
+    eval: '(list "a"'
+"#,
+        )
+        .unwrap();
+        let error = config.validate().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Invalid configuration:
+  init: line 2, column 1: unclosed (: (defun b () (a)
+  programs[0].match: line 1, column 4: unexpected ): (a))
+  programs[1].match: line 1, column 5: unterminated string: (== \"x)
+  programs[2].match: empty program
+  programs[3]: match and criteria can not be used together
+  programs[4]: match or criteria is required
+  programs[5].criteria: missing ]
+  programs[6]: criteria requires cmd
+  programs[7].cmd.eval: line 1, column 1: unclosed (: (list \"a\""
+        );
+    }
+
+    #[test]
+    fn forms_are_split_at_top_level() {
+        assert_eq!(
+            scan("(a (b)) 'c \"d e\" ; (f\n(g)").unwrap(),
+            vec![0, 8, 11, 22]
+        );
+        assert_eq!(parse("(a (b)) 'c (g)").unwrap().len(), 3);
+    }
 }
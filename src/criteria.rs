@@ -0,0 +1,229 @@
+//! i3's criteria syntax, `[class="Firefox" title="^Mozilla"]`, to match
+//! windows without writing lisp.
+
+use std::fmt::{Display, Formatter};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::i3ipc::Node;
+
+#[derive(Clone, Debug)]
+enum Rule {
+    /// `class` or `app_id`, whichever the window has.
+    App(Regex),
+    Instance(Regex),
+    Title(Regex),
+    WindowRole(Regex),
+    WindowType(Regex),
+    Shell(Regex),
+    ConMark(Regex),
+    ConId(i64),
+    Id(i64),
+}
+
+impl Rule {
+    fn new(key: &str, value: &str) -> Result<Self, String> {
+        let regex = || Regex::new(value).map_err(|e| format!("invalid regex for {}: {}", key, e));
+        let number = || {
+            value
+                .parse()
+                .map_err(|_| format!("{} must be a number, got {:?}", key, value))
+        };
+        Ok(match key {
+            "class" | "app_id" => Rule::App(regex()?),
+            "instance" => Rule::Instance(regex()?),
+            "title" => Rule::Title(regex()?),
+            "window_role" => Rule::WindowRole(regex()?),
+            "window_type" => Rule::WindowType(regex()?),
+            "shell" => Rule::Shell(regex()?),
+            "con_mark" => Rule::ConMark(regex()?),
+            "con_id" => Rule::ConId(number()?),
+            "id" => Rule::Id(number()?),
+            _ => return Err(format!("unsupported criterion {:?}", key)),
+        })
+    }
+
+    fn matches(&self, node: &Node) -> bool {
+        let properties = node.window_properties.as_ref();
+        let property =
+            |regex: &Regex, value: Option<&String>| value.is_some_and(|v| regex.is_match(v));
+        match self {
+            Rule::App(r) => property(
+                r,
+                node.app_id
+                    .as_ref()
+                    .or_else(|| properties.and_then(|p| p.class.as_ref())),
+            ),
+            Rule::Instance(r) => property(r, properties.and_then(|p| p.instance.as_ref())),
+            Rule::Title(r) => property(
+                r,
+                properties
+                    .and_then(|p| p.title.as_ref())
+                    .or(node.name.as_ref()),
+            ),
+            Rule::WindowRole(r) => property(r, properties.and_then(|p| p.window_role.as_ref())),
+            Rule::WindowType(r) => property(r, properties.and_then(|p| p.window_type.as_ref())),
+            Rule::Shell(r) => property(r, node.shell.as_ref()),
+            Rule::ConMark(r) => node.marks.iter().any(|m| r.is_match(m)),
+            Rule::ConId(id) => node.id == *id,
+            Rule::Id(id) => node.window == Some(*id),
+        }
+    }
+}
+
+/// Read a value up to the next whitespace, or a double quoted one.
+fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    let mut value = String::new();
+    if chars.peek() != Some(&'"') {
+        while let Some(c) = chars.peek().filter(|c| !c.is_whitespace()) {
+            value.push(*c);
+            chars.next();
+        }
+        return Ok(value);
+    }
+    chars.next();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') if chars.peek() == Some(&'"') => value.push(chars.next().unwrap()),
+            Some(c) => value.push(c),
+            None => return Err(format!("unterminated string {:?}", value)),
+        }
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Rule>, String> {
+    let inner = source.trim();
+    let inner = match inner.strip_prefix('[') {
+        Some(inner) => inner.strip_suffix(']').ok_or("missing ]")?,
+        None => inner,
+    };
+    let mut rules = Vec::new();
+    let mut chars = inner.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        if chars.next() != Some('=') {
+            return Err(format!("expected = after {:?}", key));
+        }
+        rules.push(Rule::new(&key, &parse_value(&mut chars)?)?);
+    }
+    if rules.is_empty() {
+        return Err("no criteria".to_string());
+    }
+    Ok(rules)
+}
+
+/// Criteria a window has to match all of, regular expressions are
+/// searched anywhere in the value like i3 does.
+///
+/// Errors are kept to be reported by [`crate::config::Config::validate`].
+#[derive(Clone, Debug)]
+pub struct Criteria {
+    source: String,
+    rules: Vec<Rule>,
+    error: Option<String>,
+}
+
+impl Criteria {
+    pub fn check(&self) -> Result<(), String> {
+        match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn matches(&self, node: &Node) -> bool {
+        self.error.is_none() && self.rules.iter().all(|r| r.matches(node))
+    }
+}
+
+impl Display for Criteria {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Criteria {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let source: String = Deserialize::deserialize(deserializer)?;
+        let (rules, error) = match parse(&source) {
+            Ok(rules) => (rules, None),
+            Err(e) => (vec![], Some(e)),
+        };
+        Ok(Criteria {
+            source,
+            rules,
+            error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    fn criteria(source: &str) -> Criteria {
+        let criteria: Criteria = serde_json::from_value(source.into()).unwrap();
+        criteria.check().unwrap();
+        criteria
+    }
+
+    fn node(value: serde_json::Value) -> Node {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn app_identity_is_normalized() {
+        let sway = node(mock::sway_window(1, "firefox"));
+        let i3 = node(mock::i3_window(2, "firefox"));
+        for source in [r#"[app_id="firefox"]"#, r#"[class="^fire"]"#, "class=fox"] {
+            let criteria = criteria(source);
+            assert!(criteria.matches(&sway), "{}", source);
+            assert!(criteria.matches(&i3), "{}", source);
+        }
+        assert!(!criteria(r#"[class="LibreWolf"]"#).matches(&sway));
+    }
+
+    #[test]
+    fn all_criteria_have_to_match() {
+        let mut window = mock::i3_window(3, "LibreWolf");
+        window["window_properties"]["title"] = "Mozilla LibreWolf".into();
+        window["window_properties"]["window_role"] = "browser".into();
+        window["marks"] = serde_json::json!(["web"]);
+        let window = node(window);
+        assert!(criteria(
+            r#"[class="LibreWolf" title="^Mozilla" window_role="browser" con_mark="web" con_id=3]"#
+        )
+        .matches(&window));
+        assert!(!criteria(r#"[class="LibreWolf" window_role="popup"]"#).matches(&window));
+        assert!(!criteria(r#"[class="LibreWolf" instance="x"]"#).matches(&window));
+        assert!(!criteria("[con_id=4]").matches(&window));
+    }
+
+    #[test]
+    fn invalid_criteria_are_reported() {
+        for (source, error) in [
+            ("", "no criteria"),
+            (r#"[class="x""#, "missing ]"),
+            (r#"[class="x]"#, r#"unterminated string "x""#),
+            ("[title]", r#"expected = after "title""#),
+            ("[floating=1]", r#"unsupported criterion "floating""#),
+            ("[con_id=abc]", r#"con_id must be a number, got "abc""#),
+        ] {
+            let criteria: Criteria = serde_json::from_value(source.into()).unwrap();
+            assert_eq!(criteria.check().unwrap_err(), error, "{}", source);
+        }
+    }
+}
@@ -1,83 +1,239 @@
-use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io::Write as _;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use anyhow::{anyhow, Context as _, Result};
+use futures::Stream;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, Serializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
 
-use anyhow::Result;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
 
-use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
-use tokio::net::UnixStream;
+/// Check that a window manager is answering on `path`.
+async fn probe(path: &Path) -> Result<(), anyhow::Error> {
+    let connection = Connection::connect(path)?;
+    timeout(PROBE_TIMEOUT, connection.get_version())
+        .await
+        .map_err(|_| anyhow!("no reply to VERSION within {:?}", PROBE_TIMEOUT))??;
+    Ok(())
+}
 
-pub async fn get_socket_path() -> Result<std::path::PathBuf, anyhow::Error> {
-    if let Ok(p) = std::env::var("I3SOCK") {
-        return Ok(std::path::PathBuf::from_str(&p).unwrap());
+/// Sockets i3 and sway create by default for the current user, newest first.
+fn runtime_sockets() -> Vec<PathBuf> {
+    // SAFETY: getuid has no preconditions and always succeeds.
+    let uid = unsafe { libc::getuid() };
+    let mut dirs = Vec::new();
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        dirs.push(PathBuf::from(dir));
     }
-    if let Ok(p) = std::env::var("SWAYSOCK") {
-        return Ok(std::path::PathBuf::from_str(&p).unwrap());
+    let run_user = PathBuf::from(format!("/run/user/{}", uid));
+    if !dirs.contains(&run_user) {
+        dirs.push(run_user);
     }
 
-    for command_name in ["i3", "sway"] {
-        let output = tokio::process::Command::new(command_name)
-            .arg("--get-socketpath")
-            .output()
-            .await?;
-        if output.status.success() {
-            return Ok(std::path::PathBuf::from_str(
-                String::from_utf8_lossy(&output.stdout).trim_end_matches('\n'),
-            )
-            .unwrap());
+    let sway_prefix = format!("sway-ipc.{}.", uid);
+    let mut sockets: Vec<(std::time::SystemTime, PathBuf)> = Vec::new();
+    for (dir, prefix, suffix) in dirs.iter().flat_map(|dir| {
+        [
+            (dir.clone(), sway_prefix.as_str(), ".sock"),
+            (dir.join("i3"), "ipc-socket.", ""),
+        ]
+    }) {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !name.starts_with(prefix) || !name.ends_with(suffix) {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.uid() != uid {
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or(std::time::UNIX_EPOCH);
+            sockets.push((modified, entry.path()));
         }
     }
+    sockets.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    sockets.into_iter().map(|(_, path)| path).collect()
+}
 
-    Err(tokio::io::Error::new(tokio::io::ErrorKind::Other, ""))?
+/// A place the IPC socket may be found.
+#[derive(Clone, Copy, Debug)]
+enum Source {
+    /// An environment variable.
+    Var(&'static str),
+    /// A window manager binary run with `--get-socketpath`.
+    Command(&'static str),
+    /// The default socket locations.
+    RuntimeDirectory,
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-#[repr(u32)]
+const SOURCES: [Source; 5] = [
+    Source::Var("I3SOCK"),
+    Source::Var("SWAYSOCK"),
+    Source::Command("i3"),
+    Source::Command("sway"),
+    Source::RuntimeDirectory,
+];
+
+impl Source {
+    /// The sockets this source names, or why it names none.
+    async fn sockets(self) -> Result<Vec<PathBuf>, String> {
+        match self {
+            Source::Var(var) => std::env::var_os(var)
+                .map(|p| vec![PathBuf::from(p)])
+                .ok_or_else(|| "not set".to_string()),
+            Source::Command(command_name) => {
+                match tokio::process::Command::new(command_name)
+                    .arg("--get-socketpath")
+                    .output()
+                    .await
+                {
+                    Ok(output) if output.status.success() => {
+                        let path = String::from_utf8_lossy(&output.stdout)
+                            .trim_end_matches('\n')
+                            .to_string();
+                        Ok(vec![PathBuf::from(path)])
+                    }
+                    Ok(output) => Err(output.status.to_string()),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            Source::RuntimeDirectory => Ok(runtime_sockets()),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Source::Var(var) => write!(f, "${}", var),
+            Source::Command(command_name) => write!(f, "{} --get-socketpath", command_name),
+            Source::RuntimeDirectory => write!(f, "runtime directory"),
+        }
+    }
+}
+
+/// Take the first socket of `sources` that answers, later sources are
+/// only looked at if the earlier ones did not.
+async fn search(sources: &[Source]) -> Result<PathBuf, anyhow::Error> {
+    let mut tried: Vec<String> = Vec::new();
+    for source in sources {
+        let paths = match source.sockets().await {
+            Ok(paths) => paths,
+            Err(e) => {
+                tried.push(format!("{}: {}", source, e));
+                continue;
+            }
+        };
+        for path in paths {
+            match probe(&path).await {
+                Ok(()) => {
+                    debug!("Using socket {} from {}", path.display(), source);
+                    return Ok(path);
+                }
+                Err(e) => tried.push(format!("{} ({}): {}", source, path.display(), e)),
+            }
+        }
+    }
+    Err(anyhow!(
+        "No running i3 or sway found, tried:\n  {}",
+        tried.join("\n  ")
+    ))
+}
+
+/// Find the IPC socket of the running window manager.
+///
+/// An explicitly given socket is used as is. Otherwise `$I3SOCK`,
+/// `$SWAYSOCK`, `i3 --get-socketpath`, `sway --get-socketpath` and the
+/// default socket locations are tried in order, taking the first socket
+/// that answers.
+pub async fn get_socket_path(explicit: Option<&Path>) -> Result<PathBuf, anyhow::Error> {
+    if let Some(path) = explicit {
+        probe(path)
+            .await
+            .with_context(|| format!("No window manager answering on {}", path.display()))?;
+        return Ok(path.into());
+    }
+    search(&SOURCES).await
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum MessageType {
-    Command = 0,
-    Workspace = 1,
-    Subscribe = 2,
-    Outputs = 3,
-    Tree = 4,
-    Marks = 5,
-    BarConfig = 6,
-    Version = 7,
-    BindingModes = 8,
-    Config = 9,
-    Tick = 10,
-    Sync = 11,
-    BindingState = 12,
-    #[serde(rename = "workspace")]
-    SubWorkspace = 0 | 1 << 31,
-    #[serde(rename = "output")]
-    SubOutput = 1 | 1 << 31,
-    #[serde(rename = "mode")]
-    SubMode = 2 | 1 << 31,
-    #[serde(rename = "window")]
-    SubWindow = 3 | 1 << 31,
-    #[serde(rename = "barconfig_update")]
-    SubBarConfig = 4 | 1 << 31,
-    #[serde(rename = "binding")]
-    SubBinding = 5 | 1 << 31,
-    #[serde(rename = "shutdown")]
-    SubShutdown = 6 | 1 << 31,
-    #[serde(rename = "tick")]
-    SubTick = 7 | 1 << 31,
+    Command,
+    Workspace,
+    Subscribe,
+    Outputs,
+    Tree,
+    Marks,
+    BarConfig,
+    Version,
+    BindingModes,
+    Config,
+    Tick,
+    Sync,
+    BindingState,
+    // sway only
+    GetInputs,
+    GetSeats,
+    SubWorkspace,
+    SubOutput,
+    SubMode,
+    SubWindow,
+    SubBarConfig,
+    SubBinding,
+    SubShutdown,
+    SubTick,
+    // sway only
+    SubInput,
+    /// A message type this implementation does not know about.
+    Unknown(u32),
 }
 
 impl MessageType {
     pub fn is_subscription(&self) -> bool {
-        ((*self as u32) & (1 << 31)) != 0
+        (u32::from(*self) & (1 << 31)) != 0
+    }
+
+    /// Name used to subscribe to this event type.
+    pub fn event_name(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::SubWorkspace => "workspace",
+            Self::SubOutput => "output",
+            Self::SubMode => "mode",
+            Self::SubWindow => "window",
+            Self::SubBarConfig => "barconfig_update",
+            Self::SubBinding => "binding",
+            Self::SubShutdown => "shutdown",
+            Self::SubTick => "tick",
+            Self::SubInput => "input",
+            _ => return None,
+        })
     }
 }
 
-impl TryFrom<u32> for MessageType {
-    type Error = &'static str;
-    fn try_from(value: u32) -> Result<Self, Self::Error> {
-        Ok(match value {
+impl From<u32> for MessageType {
+    fn from(value: u32) -> Self {
+        match value {
             0x00000000 => Self::Command,
             0x00000001 => Self::Workspace,
             0x00000002 => Self::Subscribe,
@@ -91,6 +247,8 @@ impl TryFrom<u32> for MessageType {
             0x0000000a => Self::Tick,
             0x0000000b => Self::Sync,
             0x0000000c => Self::BindingState,
+            0x00000064 => Self::GetInputs,
+            0x00000065 => Self::GetSeats,
             0x80000000 => Self::SubWorkspace,
             0x80000001 => Self::SubOutput,
             0x80000002 => Self::SubMode,
@@ -99,138 +257,1423 @@ impl TryFrom<u32> for MessageType {
             0x80000005 => Self::SubBinding,
             0x80000006 => Self::SubShutdown,
             0x80000007 => Self::SubTick,
-            _ => return Err(""),
+            0x80000015 => Self::SubInput,
+            value => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<MessageType> for u32 {
+    fn from(value: MessageType) -> Self {
+        match value {
+            MessageType::Command => 0x00000000,
+            MessageType::Workspace => 0x00000001,
+            MessageType::Subscribe => 0x00000002,
+            MessageType::Outputs => 0x00000003,
+            MessageType::Tree => 0x00000004,
+            MessageType::Marks => 0x00000005,
+            MessageType::BarConfig => 0x00000006,
+            MessageType::Version => 0x00000007,
+            MessageType::BindingModes => 0x00000008,
+            MessageType::Config => 0x00000009,
+            MessageType::Tick => 0x0000000a,
+            MessageType::Sync => 0x0000000b,
+            MessageType::BindingState => 0x0000000c,
+            MessageType::GetInputs => 0x00000064,
+            MessageType::GetSeats => 0x00000065,
+            MessageType::SubWorkspace => 0x80000000,
+            MessageType::SubOutput => 0x80000001,
+            MessageType::SubMode => 0x80000002,
+            MessageType::SubWindow => 0x80000003,
+            MessageType::SubBarConfig => 0x80000004,
+            MessageType::SubBinding => 0x80000005,
+            MessageType::SubShutdown => 0x80000006,
+            MessageType::SubTick => 0x80000007,
+            MessageType::SubInput => 0x80000015,
+            MessageType::Unknown(value) => value,
+        }
+    }
+}
+
+/// Event types serialize to the names used in `SUBSCRIBE` messages,
+/// everything else to its numeric id.
+impl Serialize for MessageType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.event_name() {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_u32(u32::from(*self)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeType {
+    Root,
+    Output,
+    Con,
+    FloatingCon,
+    Workspace,
+    Dockarea,
+    #[serde(other)]
+    Unknown,
+}
+
+/// X11 properties of a window, only present for i3 and XWayland windows.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WindowProperties {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transient_for: Option<i64>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A container in the layout tree.
+///
+/// Only the fields i3toolwait cares about are typed, everything else
+/// (including fields only one of i3 or sway sends) is kept in `extra`
+/// so that a node serializes back to what the window manager sent.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub node_type: NodeType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(default)]
+    pub rect: Rect,
+    #[serde(default)]
+    pub focused: bool,
+    #[serde(default)]
+    pub urgent: bool,
+    #[serde(default)]
+    pub marks: Vec<String>,
+    #[serde(default)]
+    pub focus: Vec<i64>,
+    #[serde(default)]
+    pub nodes: Vec<Node>,
+    #[serde(default)]
+    pub floating_nodes: Vec<Node>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_properties: Option<WindowProperties>,
+    // sway only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowChange {
+    New,
+    Close,
+    Focus,
+    Title,
+    FullscreenMode,
+    Move,
+    Floating,
+    Urgent,
+    Mark,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WindowEvent {
+    pub change: WindowChange,
+    pub container: Node,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceChange {
+    Init,
+    Empty,
+    Focus,
+    Move,
+    Rename,
+    Urgent,
+    Reload,
+    Restored,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorkspaceEvent {
+    pub change: WorkspaceChange,
+    #[serde(default)]
+    pub current: Option<Node>,
+    #[serde(default)]
+    pub old: Option<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputEvent {
+    pub change: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownChange {
+    Restart,
+    Exit,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShutdownEvent {
+    pub change: ShutdownChange,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TickEvent {
+    /// Set for the event sent right after subscribing.
+    #[serde(default)]
+    pub first: bool,
+    pub payload: String,
+}
+
+/// An input device, sway only.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Input {
+    pub identifier: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub input_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xkb_active_layout_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xkb_active_layout_index: Option<i32>,
+    #[serde(default)]
+    pub xkb_layout_names: Vec<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A seat, sway only.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Seat {
+    pub name: String,
+    pub capabilities: i32,
+    pub focus: i64,
+    #[serde(default)]
+    pub devices: Vec<Input>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputChange {
+    Added,
+    Removed,
+    XkbKeymap,
+    XkbLayout,
+    LibinputConfig,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InputEvent {
+    pub change: InputChange,
+    pub input: Input,
+}
+
+/// An event received on a subscribed connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Workspace(Box<WorkspaceEvent>),
+    Output(OutputEvent),
+    Window(Box<WindowEvent>),
+    Shutdown(ShutdownEvent),
+    Tick(TickEvent),
+    Input(Box<InputEvent>),
+    /// Any event i3toolwait does not have a typed representation for.
+    Other(MessageType, serde_json::Value),
+}
+
+impl Event {
+    pub fn parse(message_type: MessageType, payload: &[u8]) -> Result<Self, anyhow::Error> {
+        Ok(match message_type {
+            MessageType::SubWorkspace => Self::Workspace(serde_json::from_slice(payload)?),
+            MessageType::SubOutput => Self::Output(serde_json::from_slice(payload)?),
+            MessageType::SubWindow => Self::Window(serde_json::from_slice(payload)?),
+            MessageType::SubShutdown => Self::Shutdown(serde_json::from_slice(payload)?),
+            MessageType::SubTick => Self::Tick(serde_json::from_slice(payload)?),
+            MessageType::SubInput => Self::Input(serde_json::from_slice(payload)?),
+            _ => Self::Other(message_type, serde_json::from_slice(payload)?),
         })
     }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Self::Workspace(_) => MessageType::SubWorkspace,
+            Self::Output(_) => MessageType::SubOutput,
+            Self::Window(_) => MessageType::SubWindow,
+            Self::Shutdown(_) => MessageType::SubShutdown,
+            Self::Tick(_) => MessageType::SubTick,
+            Self::Input(_) => MessageType::SubInput,
+            Self::Other(message_type, _) => *message_type,
+        }
+    }
 }
 
-type SubscriptionCallback =
-    dyn Fn(
-        MessageType,
-        serde_json::Value,
-    ) -> Pin<Box<dyn std::future::Future<Output = Vec<(MessageType, Vec<u8>)>> + Send>>;
+impl Serialize for Event {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Workspace(e) => e.serialize(serializer),
+            Self::Output(e) => e.serialize(serializer),
+            Self::Window(e) => e.serialize(serializer),
+            Self::Shutdown(e) => e.serialize(serializer),
+            Self::Tick(e) => e.serialize(serializer),
+            Self::Input(e) => e.serialize(serializer),
+            Self::Other(_, v) => v.serialize(serializer),
+        }
+    }
+}
 
-pub struct Connection<'a> {
-    stream: BufStream<UnixStream>,
-    subscriptions: HashMap<MessageType, Box<&'a SubscriptionCallback>>,
+/// Reply to a single command of a `COMMAND` message.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommandOutcome {
+    pub success: bool,
+    #[serde(default)]
+    pub parse_error: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
-impl<'a> Connection<'a> {
-    pub fn connect(path: &std::path::Path) -> Result<Self, anyhow::Error> {
-        let stream = std::os::unix::net::UnixStream::connect(path)?;
-        stream.set_nonblocking(true)?;
-        let stream = BufStream::new(UnixStream::from_std(stream)?);
-        let subscriptions = HashMap::new();
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub human_readable: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loaded_config_file_name: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Output {
+    pub name: String,
+    pub active: bool,
+    #[serde(default)]
+    pub primary: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_workspace: Option<String>,
+    #[serde(default)]
+    pub rect: Rect,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Workspace {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub num: i32,
+    pub name: String,
+    pub visible: bool,
+    pub focused: bool,
+    pub urgent: bool,
+    pub rect: Rect,
+    pub output: String,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Names of all marks set on containers.
+pub type Marks = Vec<String>;
+
+/// The active binding mode.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BindingState {
+    pub name: String,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+pub(crate) type Message = (MessageType, Vec<u8>);
+
+pub(crate) async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message_type: &MessageType,
+    message: &[u8],
+) -> Result<(), anyhow::Error> {
+    writer.write_all(b"i3-ipc").await?;
+    writer.write_u32_le(message.len() as u32).await?;
+    writer.write_u32_le(u32::from(*message_type)).await?;
+    writer.write_all(message).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Message, anyhow::Error> {
+    let mut buffer = vec![0u8; 6];
+    reader.read_exact(&mut buffer).await?;
+    if buffer != b"i3-ipc" {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::Other, ""))?;
+    }
+    let message_len = reader.read_u32_le().await?;
+    let message_type = reader.read_u32_le().await?.into();
+    let mut buffer = vec![0u8; message_len as usize];
+    reader.read_exact(&mut buffer).await?;
+    Ok((message_type, buffer))
+}
+
+/// Direction of a message relative to i3toolwait.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Direction {
+    Sent,
+    Received,
+}
+
+/// One line of a transcript.
+#[derive(Serialize)]
+struct Record {
+    timestamp: f64,
+    connection: usize,
+    direction: Direction,
+    #[serde(rename = "type")]
+    message_type: u32,
+    name: String,
+    payload: serde_json::Value,
+}
+
+/// Every message sent and received, written as JSON lines for bug reports.
+///
+/// Payloads that are not JSON, like commands, are written as strings.
+#[derive(Clone)]
+pub struct Transcript {
+    file: Arc<std::sync::Mutex<std::io::BufWriter<std::fs::File>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Transcript {
+    pub fn create(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create transcript {}", path.display()))?;
         Ok(Self {
-            stream,
-            subscriptions,
+            file: Arc::new(std::sync::Mutex::new(std::io::BufWriter::new(file))),
+            connections: Arc::new(AtomicUsize::new(0)),
         })
     }
 
-    pub async fn send_message(
+    /// Number identifying a new connection in the transcript.
+    fn next_connection(&self) -> usize {
+        self.connections.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn record(
+        &self,
+        connection: usize,
+        direction: Direction,
+        message_type: MessageType,
+        payload: &[u8],
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let payload = serde_json::from_slice(payload).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(payload).to_string())
+        });
+        let record = Record {
+            timestamp,
+            connection,
+            direction,
+            message_type: message_type.into(),
+            name: format!("{:?}", message_type),
+            payload,
+        };
+        let mut file = self.file.lock().unwrap();
+        // Flushed right away, the transcript is most useful when we crash.
+        let written = serde_json::to_writer(&mut *file, &record)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(writeln!(file)?))
+            .and_then(|_| Ok(file.flush()?));
+        if let Err(e) = written {
+            warn!("Failed to write transcript: {}", e);
+        }
+    }
+}
+
+/// Where a connection records its messages, if anywhere.
+#[derive(Clone, Default)]
+struct Recorder {
+    transcript: Option<Transcript>,
+    connection: usize,
+}
+
+impl Recorder {
+    fn new(transcript: Option<&Transcript>) -> Self {
+        match transcript {
+            Some(transcript) => Self {
+                connection: transcript.next_connection(),
+                transcript: Some(transcript.clone()),
+            },
+            None => Self::default(),
+        }
+    }
+
+    fn record(&self, direction: Direction, message_type: MessageType, payload: &[u8]) {
+        if let Some(transcript) = &self.transcript {
+            transcript.record(self.connection, direction, message_type, payload);
+        }
+    }
+}
+
+/// What happens to events other than window and shutdown events once a
+/// subscriber's backlog is full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BacklogPolicy {
+    /// Drop the new event.
+    #[default]
+    Drop,
+    /// Replace queued events of the same type with the new one.
+    Coalesce,
+}
+
+/// Limits for events that were received but not yet consumed.
+///
+/// Window and shutdown events are always queued, no matter how full the
+/// backlog is, as i3toolwait must not miss them.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct Backlog {
+    pub capacity: usize,
+    /// Warn once this many events are waiting.
+    pub warn: usize,
+    pub policy: BacklogPolicy,
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            warn: 64,
+            policy: BacklogPolicy::Drop,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<Event>,
+    closed: bool,
+    backlogged: bool,
+    waker: Option<Waker>,
+}
+
+impl Queue {
+    fn push(&mut self, event: Event, backlog: &Backlog) {
+        let essential = matches!(event, Event::Window(_) | Event::Shutdown(_));
+        if !essential && self.events.len() >= backlog.capacity {
+            let message_type = event.message_type();
+            match backlog.policy {
+                BacklogPolicy::Drop => {
+                    debug!("Event backlog full, dropping {:?} event", message_type);
+                    return;
+                }
+                BacklogPolicy::Coalesce => {
+                    let queued = self.events.len();
+                    self.events.retain(|e| e.message_type() != message_type);
+                    if self.events.len() == queued {
+                        debug!("Event backlog full, dropping {:?} event", message_type);
+                        return;
+                    }
+                }
+            }
+        }
+        self.events.push_back(event);
+        if !self.backlogged && self.events.len() >= backlog.warn {
+            self.backlogged = true;
+            warn!(
+                "{} events are waiting to be processed, matchers may be too slow",
+                self.events.len()
+            );
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Subscriber {
+    events: Vec<MessageType>,
+    backlog: Backlog,
+    queue: Arc<std::sync::Mutex<Queue>>,
+}
+
+type Subscribers = Arc<std::sync::Mutex<Vec<Subscriber>>>;
+
+/// Hand an event to every subscriber interested in it, forgetting
+/// subscribers whose stream has been dropped.
+fn dispatch(subscribers: &Subscribers, event: Event) {
+    let message_type = event.message_type();
+    let mut subscribers = subscribers.lock().unwrap();
+    subscribers.retain(|s| Arc::strong_count(&s.queue) > 1);
+    for subscriber in subscribers.iter() {
+        if subscriber.events.contains(&message_type) {
+            subscriber
+                .queue
+                .lock()
+                .unwrap()
+                .push(event.clone(), &subscriber.backlog);
+        }
+    }
+}
+
+/// Read messages until the connection closes, routing events and replies
+/// to their own queues.
+///
+/// Events may arrive at any time, even between a request and its reply,
+/// so they must never be taken as the reply to a request.
+async fn demultiplex(
+    mut reader: BufReader<OwnedReadHalf>,
+    replies: mpsc::UnboundedSender<Result<Message, anyhow::Error>>,
+    subscribers: Subscribers,
+    recorder: Recorder,
+    restarts: Arc<AtomicUsize>,
+) {
+    let mut restarting = false;
+    loop {
+        let message = read_message(&mut reader).await;
+        if let Ok((message_type, payload)) = &message {
+            recorder.record(Direction::Received, *message_type, payload);
+        }
+        match message {
+            Ok((message_type, payload)) if message_type.is_subscription() => {
+                match Event::parse(message_type, &payload) {
+                    Ok(event) => {
+                        if let Event::Shutdown(ShutdownEvent {
+                            change: ShutdownChange::Restart,
+                        }) = event
+                        {
+                            restarting = true;
+                            restarts.fetch_add(1, Ordering::SeqCst);
+                        }
+                        dispatch(&subscribers, event);
+                    }
+                    Err(e) => warn!("Failed to parse {:?} event: {}", message_type, e),
+                }
+            }
+            Ok(message) => {
+                if replies.send(Ok(message)).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                let _ = replies.send(Err(e));
+                break;
+            }
+        }
+    }
+    // Ends all event streams, unless they are going to be picked up
+    // by the connection replacing this one.
+    if !restarting {
+        for subscriber in subscribers.lock().unwrap().drain(..) {
+            subscriber.queue.lock().unwrap().close();
+        }
+    }
+}
+
+/// Events of a subscription, ends when the connection closes.
+pub struct EventStream {
+    queue: Arc<std::sync::Mutex<Queue>>,
+    backlog: Backlog,
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(event) = queue.events.pop_front() {
+            if queue.events.len() < self.backlog.warn / 2 {
+                queue.backlogged = false;
+            }
+            return Poll::Ready(Some(event));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Writing half of the connection together with the queue its replies
+/// arrive on, locked as one so request/reply pairs never interleave.
+struct Channel {
+    writer: BufWriter<OwnedWriteHalf>,
+    replies: mpsc::UnboundedReceiver<Result<Message, anyhow::Error>>,
+    recorder: Recorder,
+    /// Restarts of the window manager announced before this socket was opened.
+    generation: usize,
+    /// Requests whose reply was given up on, it may still arrive.
+    abandoned: Vec<MessageType>,
+}
+
+impl Channel {
+    async fn send_message(
         &mut self,
         message_type: &MessageType,
         message: &[u8],
     ) -> Result<(), anyhow::Error> {
-        self.stream.write_all(b"i3-ipc").await?;
-        self.stream.write_u32_le(message.len() as u32).await?;
-        self.stream.write_u32_le(*message_type as u32).await?;
-        self.stream.write_all(message).await?;
-        self.stream.flush().await?;
-        Ok(())
+        self.recorder
+            .record(Direction::Sent, *message_type, message);
+        write_message(&mut self.writer, message_type, message).await
     }
 
-    pub async fn receive_message(&mut self) -> Result<(MessageType, Vec<u8>), anyhow::Error> {
-        let mut buffer = vec![0u8; 6];
-        self.stream.read_exact(&mut buffer).await?;
-        if buffer != b"i3-ipc" {
-            return Err(tokio::io::Error::new(tokio::io::ErrorKind::Other, ""))?;
-        }
-        let message_len = self.stream.read_u32_le().await?;
-        let message_type = self.stream.read_u32_le().await?.try_into().unwrap();
-        let mut buffer = vec![0u8; message_len as usize];
-        self.stream.read_exact(&mut buffer).await?;
-        Ok((message_type, buffer))
+    async fn receive_reply(&mut self) -> Result<Message, anyhow::Error> {
+        self.replies
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(anyhow!("Connection closed")))
     }
 
-    pub async fn communicate(
+    /// Send a request and wait at most `limit` for its reply.
+    ///
+    /// Only waiting is limited, the request is always sent completely.
+    async fn communicate<T: DeserializeOwned>(
         &mut self,
         message_type: &MessageType,
         message: &[u8],
-    ) -> Result<(MessageType, serde_json::Value), anyhow::Error> {
+        limit: Option<Duration>,
+    ) -> Result<T, anyhow::Error> {
         self.send_message(message_type, message).await?;
-        let (message_type, response) = self.receive_message().await?;
-        Ok((
-            message_type,
-            serde_json::from_str(String::from_utf8_lossy(response.as_ref()).as_ref())?,
-        ))
+        let (reply_type, response) = loop {
+            let reply = match limit {
+                Some(limit) => match timeout(limit, self.receive_reply()).await {
+                    Ok(reply) => reply?,
+                    Err(_) => {
+                        self.abandoned.push(*message_type);
+                        return Err(anyhow!("No reply to {:?} within {:?}", message_type, limit));
+                    }
+                },
+                None => self.receive_reply().await?,
+            };
+            match self.abandoned.iter().position(|t| *t == reply.0) {
+                Some(i) if reply.0 != *message_type => {
+                    debug!("Discarding late {:?} reply", reply.0);
+                    self.abandoned.remove(i);
+                }
+                _ => break reply,
+            }
+        };
+        if reply_type != *message_type {
+            return Err(anyhow!(
+                "Expected reply of type {:?}, got {:?}",
+                message_type,
+                reply_type
+            ));
+        }
+        Ok(serde_json::from_slice(&response)?)
     }
 
-    pub async fn subscribe(
-        &mut self,
-        events: &[MessageType],
-        callback: &'a SubscriptionCallback,
-    ) -> Result<(), anyhow::Error> {
+    async fn subscribe(&mut self, events: &[MessageType]) -> Result<(), anyhow::Error> {
         let json = serde_json::to_string(events)?;
-        let (message_type, response) = self
-            .communicate(&MessageType::Subscribe, json.as_bytes())
+        let outcome: CommandOutcome = self
+            .communicate(&MessageType::Subscribe, json.as_bytes(), None)
             .await?;
-        for s in events {
-            self.subscriptions.insert(*s, Box::new(callback));
+        if !outcome.success {
+            return Err(anyhow!("Failed to subscribe to {:?}", events));
         }
         Ok(())
     }
+}
 
-    pub async fn call_callback(
-        &mut self,
-        subscription: &MessageType,
-        response: serde_json::Value,
-    ) -> Vec<(MessageType, Vec<u8>)> {
-        let cb = self.subscriptions.get(subscription);
-        if cb.is_none() {
-            return Vec::new();
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(50);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(2);
+const RECONNECT_ATTEMPTS: usize = 8;
+/// Window managers before i3 4.15 never answer ticks.
+const TICK_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct Connection {
+    channel: Mutex<Channel>,
+    subscribers: Subscribers,
+    reader: std::sync::Mutex<JoinHandle<()>>,
+    transcript: Option<Transcript>,
+    path: std::sync::Mutex<PathBuf>,
+    /// Restarts announced on this connection or the one it duplicates.
+    restarts: Arc<AtomicUsize>,
+}
+
+impl Connection {
+    fn open(
+        path: &std::path::Path,
+        subscribers: &Subscribers,
+        transcript: Option<&Transcript>,
+        restarts: &Arc<AtomicUsize>,
+    ) -> Result<(Channel, JoinHandle<()>), anyhow::Error> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        let (reader, writer) = UnixStream::from_std(stream)?.into_split();
+        let (reply_tx, replies) = mpsc::unbounded_channel();
+        let recorder = Recorder::new(transcript);
+        let generation = restarts.load(Ordering::SeqCst);
+        let reader = tokio::spawn(demultiplex(
+            BufReader::new(reader),
+            reply_tx,
+            subscribers.clone(),
+            recorder.clone(),
+            restarts.clone(),
+        ));
+        let channel = Channel {
+            writer: BufWriter::new(writer),
+            replies,
+            recorder,
+            generation,
+            abandoned: Vec::new(),
+        };
+        Ok((channel, reader))
+    }
+
+    pub fn connect(path: &std::path::Path) -> Result<Self, anyhow::Error> {
+        Self::connect_with_transcript(path, None)
+    }
+
+    /// Connect and record every message of this connection, and of the
+    /// ones replacing it after a restart, to `transcript`.
+    pub fn connect_with_transcript(
+        path: &std::path::Path,
+        transcript: Option<Transcript>,
+    ) -> Result<Self, anyhow::Error> {
+        Self::connect_sharing(path, transcript, Arc::default())
+    }
+
+    fn connect_sharing(
+        path: &std::path::Path,
+        transcript: Option<Transcript>,
+        restarts: Arc<AtomicUsize>,
+    ) -> Result<Self, anyhow::Error> {
+        let subscribers = Subscribers::default();
+        let (channel, reader) = Self::open(path, &subscribers, transcript.as_ref(), &restarts)?;
+        Ok(Self {
+            channel: Mutex::new(channel),
+            subscribers,
+            reader: std::sync::Mutex::new(reader),
+            transcript,
+            path: std::sync::Mutex::new(path.to_path_buf()),
+            restarts,
+        })
+    }
+
+    /// Open another connection to the same window manager, so requests
+    /// can be sent without waiting for the ones on this connection.
+    ///
+    /// Restarts announced on this connection apply to the duplicate too,
+    /// which does not need to subscribe to shutdown events itself.
+    pub fn duplicate(&self) -> Result<Self, anyhow::Error> {
+        let path = self.path.lock().unwrap().clone();
+        Self::connect_sharing(&path, self.transcript.clone(), self.restarts.clone())
+    }
+
+    /// Whether the window manager restarted since `channel` was opened.
+    fn outdated(&self, channel: &Channel) -> bool {
+        self.restarts.load(Ordering::SeqCst) > channel.generation
+    }
+
+    /// Replace the underlying socket after the window manager restarted.
+    ///
+    /// The socket is looked up again and retried with backoff until the
+    /// window manager accepts connections, giving up after
+    /// `RECONNECT_ATTEMPTS` attempts. Existing event streams keep working,
+    /// their subscriptions are renewed on the new socket.
+    ///
+    /// Does nothing if the connection was already replaced since the
+    /// restart, requests do that themselves when the socket closes.
+    pub async fn reconnect(&self, socket: Option<&Path>) -> Result<(), anyhow::Error> {
+        // Holding the lock makes requests wait until we are connected again.
+        let mut channel = self.channel.lock().await;
+        if !self.outdated(&channel) {
+            debug!("Already reconnected");
+            return Ok(());
         }
-        (*cb.unwrap())(*subscription, response).await
+        self.reopen(&mut channel, socket).await
     }
 
-    pub async fn run(
-        &mut self,
-        rx: &mut tokio::sync::broadcast::Receiver<()>,
+    async fn reopen(
+        &self,
+        channel: &mut Channel,
+        socket: Option<&Path>,
     ) -> Result<(), anyhow::Error> {
-        loop {
-            let stop_task = rx.recv();
-            let receive_message_task = self.receive_message();
-            let result = tokio::select! {
-                _ = stop_task => {return Ok(())},
-                result = receive_message_task => result?,
+        let mut delay = RECONNECT_DELAY_MIN;
+        let mut attempts = 0;
+        let ((new_channel, reader), path) = loop {
+            // Boxed, finding the socket sends requests itself.
+            let opened = match Box::pin(get_socket_path(socket)).await {
+                Ok(path) => Self::open(
+                    &path,
+                    &self.subscribers,
+                    self.transcript.as_ref(),
+                    &self.restarts,
+                )
+                .map(|opened| (opened, path)),
+                Err(e) => Err(e),
             };
-            let (message_type, response) = result;
-            if !message_type.is_subscription() {
-                continue;
+            attempts += 1;
+            match opened {
+                Ok(opened) => break opened,
+                Err(e) if attempts == RECONNECT_ATTEMPTS => {
+                    return Err(e.context(format!(
+                        "Window manager did not come back after {} attempts",
+                        attempts
+                    )));
+                }
+                Err(e) => {
+                    debug!("Reconnecting failed, retrying in {:?}: {}", delay, e);
+                    tokio::time::sleep(delay).await;
+                    delay = std::cmp::min(delay * 2, RECONNECT_DELAY_MAX);
+                }
             }
+        };
+        *channel = new_channel;
+        debug!("Reconnected to {}", path.display());
+        *self.path.lock().unwrap() = path;
+        std::mem::replace(&mut *self.reader.lock().unwrap(), reader).abort();
 
-            let json_response =
-                serde_json::from_str(String::from_utf8_lossy(response.as_ref()).as_ref())?;
-            let messages: Vec<(MessageType, Vec<u8>)> =
-                self.call_callback(&message_type, json_response).await;
-            for (message_type, message) in messages {
-                // TODO maybe log responses?
-                self.communicate(&message_type, &message).await?;
+        let mut events: Vec<MessageType> = Vec::new();
+        for subscriber in self.subscribers.lock().unwrap().iter() {
+            for event in subscriber.events.iter() {
+                if !events.contains(event) {
+                    events.push(*event);
+                }
             }
         }
+        if !events.is_empty() {
+            channel.subscribe(&events).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn communicate<T: DeserializeOwned>(
+        &self,
+        message_type: &MessageType,
+        message: &[u8],
+    ) -> Result<T, anyhow::Error> {
+        self.request(message_type, message, None).await
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        message_type: &MessageType,
+        message: &[u8],
+        limit: Option<Duration>,
+    ) -> Result<T, anyhow::Error> {
+        let mut channel = self.channel.lock().await;
+        match channel.communicate(message_type, message, limit).await {
+            // The socket closed because of the restart, the request is sent
+            // again once the window manager is back.
+            Err(e) if self.outdated(&channel) => {
+                debug!("Connection lost while restarting, reconnecting: {}", e);
+                let path = self.path.lock().unwrap().clone();
+                self.reopen(&mut channel, Some(&path)).await?;
+                channel.communicate(message_type, message, limit).await
+            }
+            result => result,
+        }
+    }
+
+    pub async fn run_command(&self, command: &str) -> Result<Vec<CommandOutcome>, anyhow::Error> {
+        self.communicate(&MessageType::Command, command.as_bytes())
+            .await
+    }
+
+    pub async fn get_version(&self) -> Result<Version, anyhow::Error> {
+        self.communicate(&MessageType::Version, b"").await
+    }
+
+    /// Send a tick event with `payload` to all clients subscribed to ticks.
+    ///
+    /// Fails if the window manager does not answer within `TICK_TIMEOUT`.
+    pub async fn send_tick(&self, payload: &str) -> Result<(), anyhow::Error> {
+        let outcome: CommandOutcome = self
+            .request(&MessageType::Tick, payload.as_bytes(), Some(TICK_TIMEOUT))
+            .await?;
+        if !outcome.success {
+            return Err(anyhow!("Failed to send tick {}", payload));
+        }
+        Ok(())
+    }
+
+    /// Subscribe to `events`, each call gets its own independent stream.
+    pub async fn subscribe(&self, events: &[MessageType]) -> Result<EventStream, anyhow::Error> {
+        self.subscribe_with(events, Backlog::default()).await
+    }
+
+    /// Like [`Connection::subscribe`], with custom limits on unconsumed events.
+    pub async fn subscribe_with(
+        &self,
+        events: &[MessageType],
+        backlog: Backlog,
+    ) -> Result<EventStream, anyhow::Error> {
+        let queue = Arc::new(std::sync::Mutex::new(Queue::default()));
+        // Register first, events can arrive before the reply does.
+        self.subscribers.lock().unwrap().push(Subscriber {
+            events: events.to_vec(),
+            backlog,
+            queue: queue.clone(),
+        });
+        self.channel.lock().await.subscribe(events).await?;
+        Ok(EventStream { queue, backlog })
     }
 }
 
-impl<'a> Clone for Connection<'a> {
-    fn clone(&self) -> Self {
-        let path: std::path::PathBuf = self
-            .stream
-            .get_ref()
-            .peer_addr()
-            .unwrap()
-            .as_pathname()
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Ok(reader) = self.reader.get_mut() {
+            reader.abort();
+        }
+    }
+}
+
+// Typed queries of the window manager state.
+impl Connection {
+    pub async fn get_tree(&self) -> Result<Node, anyhow::Error> {
+        self.communicate(&MessageType::Tree, b"").await
+    }
+
+    pub async fn get_workspaces(&self) -> Result<Vec<Workspace>, anyhow::Error> {
+        self.communicate(&MessageType::Workspace, b"").await
+    }
+
+    pub async fn get_outputs(&self) -> Result<Vec<Output>, anyhow::Error> {
+        self.communicate(&MessageType::Outputs, b"").await
+    }
+
+    pub async fn get_inputs(&self) -> Result<Vec<Input>, anyhow::Error> {
+        self.communicate(&MessageType::GetInputs, b"").await
+    }
+
+    pub async fn get_seats(&self) -> Result<Vec<Seat>, anyhow::Error> {
+        self.communicate(&MessageType::GetSeats, b"").await
+    }
+
+    pub async fn get_marks(&self) -> Result<Marks, anyhow::Error> {
+        self.communicate(&MessageType::Marks, b"").await
+    }
+
+    pub async fn get_binding_state(&self) -> Result<BindingState, anyhow::Error> {
+        self.communicate(&MessageType::BindingState, b"").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockServer};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn events_reach_every_subscriber() {
+        let server = MockServer::start().unwrap();
+        let connection = Connection::connect(server.path()).unwrap();
+        let mut windows = connection
+            .subscribe(&[MessageType::SubWindow])
+            .await
+            .unwrap();
+        let mut all = connection
+            .subscribe(&[MessageType::SubWindow, MessageType::SubShutdown])
+            .await
+            .unwrap();
+
+        let (message_type, payload) = mock::window_event("new", mock::sway_window(7, "foot"));
+        server.send_event(message_type, payload);
+        server.send_event(
+            MessageType::SubShutdown,
+            serde_json::json!({"change": "exit"}),
+        );
+
+        for stream in [&mut windows, &mut all] {
+            match stream.next().await.unwrap() {
+                Event::Window(e) => {
+                    assert_eq!(e.change, WindowChange::New);
+                    assert_eq!(e.container.app_id.as_deref(), Some("foot"));
+                }
+                e => panic!("unexpected event {:?}", e),
+            }
+        }
+        assert_eq!(
+            all.next().await.unwrap(),
+            Event::Shutdown(ShutdownEvent {
+                change: ShutdownChange::Exit
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_streams_get_no_events() {
+        let server = MockServer::start().unwrap();
+        let connection = Connection::connect(server.path()).unwrap();
+        let windows = connection
+            .subscribe(&[MessageType::SubWindow])
+            .await
+            .unwrap();
+        let mut ticks = connection.subscribe(&[MessageType::SubTick]).await.unwrap();
+        drop(windows);
+
+        let (message_type, payload) = mock::window_event("new", mock::sway_window(7, "foot"));
+        server.send_event(message_type, payload);
+        connection.send_tick("after").await.unwrap();
+        while let Some(Event::Tick(tick)) = ticks.next().await {
+            if tick.payload == "after" {
+                break;
+            }
+        }
+
+        let subscribers = connection.subscribers.lock().unwrap();
+        assert_eq!(subscribers.len(), 1);
+        assert!(subscribers[0].queue.lock().unwrap().events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_reconnect_after_a_restart() {
+        let server = MockServer::start().unwrap();
+        let connection = Connection::connect(server.path()).unwrap();
+        let mut events = connection
+            .subscribe(&[MessageType::SubWindow, MessageType::SubShutdown])
+            .await
+            .unwrap();
+
+        server.restart().await;
+        assert_eq!(
+            events.next().await.unwrap(),
+            Event::Shutdown(ShutdownEvent {
+                change: ShutdownChange::Restart
+            })
+        );
+        // The request finds the socket closed and reconnects.
+        assert!(connection.run_command("nop").await.unwrap()[0].success);
+        // Handling the restart event afterwards keeps the new socket.
+        connection.reconnect(None).await.unwrap();
+
+        let (message_type, payload) = mock::window_event("new", mock::sway_window(7, "foot"));
+        server.send_event(message_type, payload);
+        match events.next().await.unwrap() {
+            Event::Window(e) => assert_eq!(e.container.id, 7),
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert_eq!(server.commands(), vec!["nop"]);
+    }
+
+    #[tokio::test]
+    async fn late_tick_replies_are_discarded() {
+        let server = MockServer::start().unwrap();
+        server.delay_command("late", Duration::from_millis(700));
+        let connection = Connection::connect(server.path()).unwrap();
+
+        assert!(connection.send_tick("late").await.is_err());
+        // The reply to the tick arrives first and must not be taken for this one.
+        assert!(connection.run_command("nop").await.unwrap()[0].success);
+        assert_eq!(server.ticks(), vec!["late"]);
+    }
+
+    #[tokio::test]
+    async fn reconnecting_gives_up() {
+        let server = MockServer::start().unwrap();
+        let path = server.path().to_path_buf();
+        let connection = Connection::connect(&path).unwrap();
+        let mut events = connection
+            .subscribe(&[MessageType::SubShutdown])
+            .await
+            .unwrap();
+
+        server.restart().await;
+        events.next().await.unwrap();
+        drop(server);
+
+        let e = connection.reconnect(Some(&path)).await.unwrap_err();
+        assert!(
+            e.to_string().contains("did not come back after 8 attempts"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn full_backlog_keeps_window_events() {
+        let window = Event::parse(
+            MessageType::SubWindow,
+            mock::window_event("new", mock::sway_window(1, "foot"))
+                .1
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap();
+        let output = |change: &str| {
+            Event::Output(OutputEvent {
+                change: change.to_string(),
+            })
+        };
+        let mut backlog = Backlog {
+            capacity: 2,
+            warn: 2,
+            policy: BacklogPolicy::Drop,
+        };
+
+        let mut queue = Queue::default();
+        for event in [output("a"), output("b"), output("c"), window.clone()] {
+            queue.push(event, &backlog);
+        }
+        assert_eq!(queue.events, vec![output("a"), output("b"), window.clone()]);
+
+        backlog.policy = BacklogPolicy::Coalesce;
+        let mut queue = Queue::default();
+        for event in [output("a"), window.clone(), output("b"), output("c")] {
+            queue.push(event, &backlog);
+        }
+        assert_eq!(queue.events, vec![window, output("c")]);
+    }
+
+    #[tokio::test]
+    async fn explicit_socket_is_probed() {
+        let server = MockServer::start().unwrap();
+        assert_eq!(
+            get_socket_path(Some(server.path())).await.unwrap(),
+            server.path()
+        );
+        let missing = server.path().with_extension("missing");
+        assert!(get_socket_path(Some(&missing)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sockets_are_searched_in_order() {
+        let server = MockServer::start().unwrap();
+        let missing = server.path().with_extension("missing");
+        std::env::set_var("I3TOOLWAIT_TEST_LIVE_SOCK", server.path());
+        std::env::set_var("I3TOOLWAIT_TEST_DEAD_SOCK", &missing);
+        std::env::remove_var("I3TOOLWAIT_TEST_UNSET_SOCK");
+
+        let found = search(&[
+            Source::Var("I3TOOLWAIT_TEST_UNSET_SOCK"),
+            Source::Var("I3TOOLWAIT_TEST_LIVE_SOCK"),
+            Source::Command("i3toolwait-test-no-such-command"),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(found, server.path());
+
+        let error = search(&[
+            Source::Var("I3TOOLWAIT_TEST_UNSET_SOCK"),
+            Source::Var("I3TOOLWAIT_TEST_DEAD_SOCK"),
+            Source::Command("i3toolwait-test-no-such-command"),
+        ])
+        .await
+        .unwrap_err()
+        .to_string();
+        let lines: Vec<&str> = error.lines().collect();
+        assert_eq!(lines[0], "No running i3 or sway found, tried:");
+        assert_eq!(lines[1], "  $I3TOOLWAIT_TEST_UNSET_SOCK: not set");
+        assert!(
+            lines[2].starts_with(&format!(
+                "  $I3TOOLWAIT_TEST_DEAD_SOCK ({}): ",
+                missing.display()
+            )),
+            "{}",
+            error
+        );
+        assert!(
+            lines[3].starts_with("  i3toolwait-test-no-such-command --get-socketpath: "),
+            "{}",
+            error
+        );
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn unknown_message_types_round_trip() {
+        for id in [0x00000065, 0x80000015, 0x0000002a, 0x80000099] {
+            assert_eq!(u32::from(MessageType::from(id)), id);
+        }
+        assert_eq!(
+            MessageType::from(0x80000099),
+            MessageType::Unknown(0x80000099)
+        );
+        assert!(MessageType::Unknown(0x80000099).is_subscription());
+
+        let event = Event::parse(
+            MessageType::from(0x80000015),
+            br#"{"change": "xkb_layout", "input": {"identifier": "1:1:kbd", "name": "kbd", "type": "keyboard", "xkb_active_layout_name": "German"}}"#,
+        )
+        .unwrap();
+        match event {
+            Event::Input(e) => {
+                assert_eq!(e.input.xkb_active_layout_name.as_deref(), Some("German"))
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        let event = Event::parse(MessageType::from(0x80000099), b"{}").unwrap();
+        assert_eq!(event.message_type(), MessageType::Unknown(0x80000099));
+    }
+
+    #[tokio::test]
+    async fn state_of_sway_is_queried() {
+        let server = MockServer::start().unwrap();
+        let keyboard = serde_json::json!({
+            "identifier": "1:1:AT_Translated_Set_2_keyboard",
+            "name": "AT Translated Set 2 keyboard",
+            "type": "keyboard",
+            "vendor": 1,
+            "product": 1,
+            "xkb_active_layout_name": "English (US)",
+            "xkb_layout_names": ["English (US)", "German"],
+            "xkb_active_layout_index": 0,
+            "libinput": {"send_events": "enabled"},
+        });
+        server.set_reply(MessageType::GetInputs, serde_json::json!([keyboard]));
+        server.set_reply(
+            MessageType::GetSeats,
+            serde_json::json!([{"name": "seat0", "capabilities": 3, "focus": 7, "devices": [keyboard]}]),
+        );
+        server.set_reply(
+            MessageType::Outputs,
+            serde_json::json!([{
+                "name": "eDP-1",
+                "active": true,
+                "current_workspace": "1",
+                "rect": {"x": 0, "y": 0, "width": 1920, "height": 1080},
+            }]),
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        let inputs = connection.get_inputs().await.unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].input_type, "keyboard");
+        assert_eq!(inputs[0].xkb_layout_names, vec!["English (US)", "German"]);
+        assert!(inputs[0].extra.contains_key("libinput"));
+        let seats = connection.get_seats().await.unwrap();
+        assert_eq!(seats[0].name, "seat0");
+        assert_eq!(seats[0].devices, inputs);
+        let outputs = connection.get_outputs().await.unwrap();
+        assert_eq!(outputs[0].current_workspace.as_deref(), Some("1"));
+        assert_eq!(outputs[0].rect.width, 1920);
+        assert!(connection.get_marks().await.unwrap().is_empty());
+        assert_eq!(
+            connection.get_binding_state().await.unwrap().name,
+            "default"
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_are_typed() {
+        let server = MockServer::start().unwrap();
+        server.set_reply(
+            MessageType::Tree,
+            mock::tree(vec![
+                mock::i3_window(5, "Firefox"),
+                mock::sway_window(6, "foot"),
+            ]),
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        assert_eq!(connection.get_version().await.unwrap().major, 4);
+        let tree = connection.get_tree().await.unwrap();
+        let workspace = &tree.nodes[0].nodes[0];
+        assert_eq!(workspace.node_type, NodeType::Workspace);
+        let windows = &workspace.nodes;
+        assert_eq!(windows.len(), 2);
+        assert_eq!(
+            windows[0]
+                .window_properties
+                .as_ref()
+                .unwrap()
+                .class
+                .as_deref(),
+            Some("Firefox")
+        );
+        assert_eq!(windows[1].pid, Some(1006));
+        let outcomes = connection.run_command("nop; nop").await.unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(|o| o.success));
+    }
+
+    #[tokio::test]
+    async fn transcript_records_both_directions() {
+        let server = MockServer::start().unwrap();
+        let path = server.path().with_extension("jsonl");
+        let transcript = Transcript::create(&path).unwrap();
+        let connection =
+            Connection::connect_with_transcript(server.path(), Some(transcript)).unwrap();
+        connection.run_command("nop").await.unwrap();
+        connection.get_version().await.unwrap();
+
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)
             .unwrap()
-            .into();
-        Self::connect(path.as_ref()).unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let _ = std::fs::remove_file(&path);
+        let summary: Vec<(&str, &str)> = records
+            .iter()
+            .map(|r| {
+                (
+                    r["direction"].as_str().unwrap(),
+                    r["name"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("sent", "Command"),
+                ("received", "Command"),
+                ("sent", "Version"),
+                ("received", "Version"),
+            ]
+        );
+        assert_eq!(records[0]["payload"], "nop");
+        assert_eq!(records[1]["payload"][0]["success"], true);
+        assert_eq!(records[3]["payload"]["major"], 4);
+        assert!(records.iter().all(|r| r["connection"] == 0));
     }
 }
@@ -1,8 +1,21 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
-use rust_lisp::model::{reference, reference::Reference, Env, FloatType, IntType, List, Value};
+use regex::Regex;
+use serde::Deserialize;
 
-fn serde_lisp_value(value: &serde_json::Value) -> Value {
+use crate::i3ipc::{Connection, MessageType};
+use rust_lisp::model::{
+    reference, reference::Reference, Env, FloatType, IntType, List, RuntimeError, Symbol, Value,
+};
+
+/// Convert JSON to a lisp value.
+///
+/// Integers lisp can not represent, like the container ids i3 derives from
+/// pointers, become strings of their digits so they are formatted back to
+/// the same number.
+pub fn serde_lisp_value(value: &serde_json::Value) -> Value {
     match value {
         serde_json::Value::Null => Value::NIL,
         serde_json::Value::Bool(b) => {
@@ -13,10 +26,17 @@ fn serde_lisp_value(value: &serde_json::Value) -> Value {
             }
         }
         serde_json::Value::Number(n) => {
-            if n.is_i64() {
-                Value::Int(n.as_i64().unwrap() as IntType)
-            } else if n.is_u64() {
-                Value::Int(n.as_u64().unwrap() as IntType)
+            let int = if let Some(i) = n.as_i64() {
+                IntType::try_from(i).ok()
+            } else if let Some(u) = n.as_u64() {
+                IntType::try_from(u).ok()
+            } else {
+                None
+            };
+            if let Some(i) = int {
+                Value::Int(i)
+            } else if n.is_i64() || n.is_u64() {
+                Value::String(n.to_string())
             } else if n.is_f64() {
                 Value::Float(n.as_f64().unwrap() as FloatType)
             } else {
@@ -43,77 +63,1264 @@ fn serde_lisp_value(value: &serde_json::Value) -> Value {
     }
 }
 
-pub fn env(value: &serde_json::Value) -> Env {
-    let mut environment = rust_lisp::default_env();
-    environment.define(
-        rust_lisp::model::Symbol::from("__input__"),
-        serde_lisp_value(value),
-    );
-    environment.define(
-        rust_lisp::model::Symbol::from("load"),
-        rust_lisp::model::Value::NativeClosure(reference::new(
-            move |e: Reference<rust_lisp::model::Env>, args: Vec<rust_lisp::model::Value>| {
-                let path: &String =
-                    rust_lisp::utils::require_typed_arg::<&String>("load", &args, 0)?;
-                let path = (*path).as_str().split('.');
-                let mut i: rust_lisp::model::Value = reference::borrow(&e)
-                    .get(&rust_lisp::model::Symbol::from("__input__"))
-                    .unwrap();
-                for p in path
-                    .into_iter()
-                    .filter(|x| !(*x).eq(""))
-                    .map(|x| rust_lisp::model::Value::String(x.into()))
-                {
-                    match i {
-                        rust_lisp::model::Value::HashMap(x) => {
-                            if let Some(_i) = reference::borrow(&x).get(&p) {
-                                i = _i.clone();
-                            } else {
-                                return Err(rust_lisp::model::RuntimeError {
-                                    msg: format!(r#"No such key {:?}"#, p).into(),
-                                });
-                            }
-                        }
-                        _ => {
-                            return Err(rust_lisp::model::RuntimeError {
-                                msg: format!(r#"No such key {:?}"#, p).into(),
-                            })
-                        }
+/// Convert a lisp value back to JSON, fails for functions and macros.
+fn lisp_serde_value(value: &Value) -> Result<serde_json::Value, RuntimeError> {
+    Ok(match value {
+        Value::True => serde_json::Value::Bool(true),
+        Value::False => serde_json::Value::Bool(false),
+        Value::Int(i) => serde_json::Value::from(*i),
+        Value::Float(f) => serde_json::Value::from(*f),
+        Value::String(s) => serde_json::Value::String(s.clone()),
+        Value::Symbol(s) => serde_json::Value::String(s.to_string()),
+        Value::List(l) => serde_json::Value::Array(
+            l.into_iter()
+                .map(|v| lisp_serde_value(&Value::clone(&v)))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::HashMap(m) => serde_json::Value::Object(
+            reference::borrow(m)
+                .iter()
+                .map(|(k, v)| {
+                    let k = match k {
+                        Value::String(s) => s.clone(),
+                        k => k.to_string(),
                     };
+                    Ok((k, lisp_serde_value(v)?))
+                })
+                .collect::<Result<_, RuntimeError>>()?,
+        ),
+        v => {
+            return Err(RuntimeError {
+                msg: format!("{} cannot be stored", v),
+            })
+        }
+    })
+}
+
+/// One step of a path given to `load`, `load-or` and `has-key`.
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(i64),
+    Wildcard,
+}
+
+/// Read a double quoted string, the opening quote already consumed.
+fn parse_quoted(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => s.push(chars.next()?),
+            c => s.push(c),
+        }
+    }
+}
+
+/// Parse paths like `.container.nodes[0].name`, `.marks[-1]`,
+/// `.nodes[*].id` or `.container."key.with.dots"`.
+fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.peek().copied() {
+        match c {
+            '.' => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let key = parse_quoted(&mut chars)
+                    .ok_or_else(|| format!("Unterminated quote in path {:?}", path))?;
+                segments.push(Segment::Key(key));
+            }
+            '[' => {
+                chars.next();
+                let segment = if chars.peek() == Some(&'"') {
+                    chars.next();
+                    parse_quoted(&mut chars)
+                        .map(Segment::Key)
+                        .ok_or_else(|| format!("Unterminated quote in path {:?}", path))?
+                } else {
+                    let mut index = String::new();
+                    while let Some(c) = chars.peek().filter(|c| **c != ']') {
+                        index.push(*c);
+                        chars.next();
+                    }
+                    match index.trim() {
+                        "*" => Segment::Wildcard,
+                        i => Segment::Index(i.parse().map_err(|_| {
+                            format!("Invalid index {:?} in path {:?}", index, path)
+                        })?),
+                    }
+                };
+                if chars.next() != Some(']') {
+                    return Err(format!("Missing ] in path {:?}", path));
                 }
-                Ok(i)
-            },
+                segments.push(segment);
+            }
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = chars.peek().filter(|c| **c != '.' && **c != '[') {
+                    key.push(*c);
+                    chars.next();
+                }
+                segments.push(Segment::Key(key));
+            }
+        }
+    }
+    Ok(segments)
+}
+
+/// The value at `segments` below `value`, if there is one.
+///
+/// A wildcard collects the matches of the rest of the path for every
+/// element of a list, elements without a match are left out.
+fn walk(value: &Value, segments: &[Segment]) -> Option<Value> {
+    let Some((segment, rest)) = segments.split_first() else {
+        return Some(value.clone());
+    };
+    match (segment, value) {
+        (Segment::Key(key), Value::HashMap(map)) => {
+            let v = reference::borrow(map)
+                .get(&Value::String(key.clone()))
+                .cloned()?;
+            walk(&v, rest)
+        }
+        (Segment::Index(index), Value::List(list)) => {
+            let items: Vec<Value> = list.into_iter().map(|v| Value::clone(&v)).collect();
+            let index = if *index < 0 {
+                items.len() as i64 + index
+            } else {
+                *index
+            };
+            walk(items.get(usize::try_from(index).ok()?)?, rest)
+        }
+        (Segment::Wildcard, Value::List(list)) => Some(Value::List(
+            list.into_iter()
+                .filter_map(|v| walk(&Value::clone(&v), rest))
+                .collect(),
         )),
-    );
-    environment.define(
-        rust_lisp::model::Symbol::from("has-key"),
-        rust_lisp::model::Value::NativeClosure(reference::new(
-            move |e: Reference<rust_lisp::model::Env>, args: Vec<rust_lisp::model::Value>| {
-                let path: &String =
-                    rust_lisp::utils::require_typed_arg::<&String>("has-key", &args, 0)?;
-                let path = (*path).as_str().split('.');
-                let mut i: rust_lisp::model::Value = reference::borrow(&e)
-                    .get(&rust_lisp::model::Symbol::from("__input__"))
-                    .unwrap();
-                for p in path
-                    .into_iter()
-                    .filter(|x| !(*x).eq(""))
-                    .map(|x| rust_lisp::model::Value::String(x.into()))
-                {
-                    match i {
-                        rust_lisp::model::Value::HashMap(x) => {
-                            if let Some(_i) = reference::borrow(&x).get(&p) {
-                                i = _i.clone();
-                            } else {
-                                return Ok(rust_lisp::model::Value::False);
-                            }
-                        }
-                        _ => return Ok(rust_lisp::model::Value::False),
-                    };
+        _ => None,
+    }
+}
+
+/// Look up the path in the first argument below the value in argument
+/// `source`, or below `__input__` if there are fewer arguments.
+fn lookup(
+    name: &str,
+    env: &Reference<Env>,
+    args: &[Value],
+    source: usize,
+) -> Result<Option<Value>, RuntimeError> {
+    let path = string_arg(name, args, 0)?;
+    let segments = parse_path(path).map_err(|msg| RuntimeError { msg })?;
+    let input = match args.get(source) {
+        Some(value) => value.clone(),
+        None => reference::borrow(env)
+            .get(&Symbol::from("__input__"))
+            .ok_or_else(|| RuntimeError {
+                msg: "No input to load from".into(),
+            })?,
+    };
+    Ok(walk(&input, &segments))
+}
+
+/// State shared by the environments of one run.
+#[derive(Clone, Default)]
+pub struct Context {
+    regexes: Arc<Mutex<HashMap<String, Regex>>>,
+    connection: Option<Arc<Connection>>,
+    replies: Arc<Mutex<HashMap<MessageType, serde_json::Value>>>,
+    store: Arc<Mutex<Store>>,
+    usage: Arc<Mutex<Usage>>,
+    /// Where `include` looks for relative paths.
+    directory: Option<PathBuf>,
+}
+
+/// Limits for a single evaluation of `init` or a matcher.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct Budget {
+    /// Calls of functions defined with `defun` or `lambda`, builtins are
+    /// not counted.
+    pub steps: u64,
+    /// Calls of those functions in progress at the same time.
+    pub depth: u64,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            steps: 100_000,
+            depth: 100,
+        }
+    }
+}
+
+/// How much of the budget the current evaluation used.
+#[derive(Default)]
+struct Usage {
+    budget: Budget,
+    steps: u64,
+    depth: u64,
+    exhausted: bool,
+}
+
+impl Usage {
+    fn enter(&mut self) -> Result<(), RuntimeError> {
+        self.steps += 1;
+        self.depth += 1;
+        let msg = if self.steps > self.budget.steps {
+            format!("Evaluation exceeded {} steps", self.budget.steps)
+        } else if self.depth > self.budget.depth {
+            format!("Evaluation exceeded a depth of {}", self.budget.depth)
+        } else {
+            return Ok(());
+        };
+        self.exhausted = true;
+        Err(RuntimeError { msg })
+    }
+}
+
+/// Values matchers keep for the whole run.
+#[derive(Default)]
+struct Store {
+    shared: HashMap<String, serde_json::Value>,
+    programs: HashMap<String, HashMap<String, serde_json::Value>>,
+    /// The program whose matcher is being evaluated.
+    program: Option<String>,
+}
+
+impl Store {
+    fn program(
+        &mut self,
+        name: &str,
+    ) -> Result<&mut HashMap<String, serde_json::Value>, RuntimeError> {
+        let program = self.program.clone().ok_or_else(|| RuntimeError {
+            msg: format!("Function \"{}\" can only be used in a matcher", name),
+        })?;
+        Ok(self.programs.entry(program).or_default())
+    }
+}
+
+impl Context {
+    /// A context whose `i3-*` builtins query the window manager on
+    /// `connection`.
+    pub fn with_connection(connection: Arc<Connection>) -> Self {
+        Self {
+            connection: Some(connection),
+            ..Self::default()
+        }
+    }
+
+    /// Resolve relative paths given to `include` in `directory`, instead
+    /// of the current directory.
+    pub fn in_directory(self, directory: Option<PathBuf>) -> Self {
+        Self { directory, ..self }
+    }
+
+    pub fn connection(&self) -> Option<&Arc<Connection>> {
+        self.connection.as_ref()
+    }
+
+    /// Make `self-get` and `self-set` refer to the storage of `program`.
+    pub fn set_program(&self, program: Option<&str>) {
+        self.store.lock().unwrap().program = program.map(str::to_string);
+    }
+
+    /// Forget the replies cached for the previous event.
+    fn next_event(&self) {
+        self.replies.lock().unwrap().clear();
+    }
+
+    /// Reply to a request without payload, only sent once per event.
+    ///
+    /// Natives are synchronous, so this blocks the calling thread on the
+    /// request, which requires a multi-threaded runtime.
+    fn query(&self, message_type: MessageType) -> Result<Value, RuntimeError> {
+        let mut replies = self.replies.lock().unwrap();
+        if let Some(reply) = replies.get(&message_type) {
+            return Ok(serde_lisp_value(reply));
+        }
+        let connection = self.connection.as_ref().ok_or_else(|| RuntimeError {
+            msg: "Not connected to a window manager".into(),
+        })?;
+        let handle = tokio::runtime::Handle::try_current()
+            .ok()
+            .filter(|h| h.runtime_flavor() != tokio::runtime::RuntimeFlavor::CurrentThread)
+            .ok_or_else(|| RuntimeError {
+                msg: format!(
+                    "Cannot query {:?} outside of a threaded runtime",
+                    message_type
+                ),
+            })?;
+        let reply =
+            tokio::task::block_in_place(|| handle.block_on(state(connection, message_type)))
+                .map_err(|e| RuntimeError {
+                    msg: format!("Failed to query {:?}: {}", message_type, e),
+                })?;
+        let value = serde_lisp_value(&reply);
+        replies.insert(message_type, reply);
+        Ok(value)
+    }
+    /// Compile `pattern` on first use, later uses share the compiled regex.
+    fn regex(&self, pattern: &str) -> Result<Regex, RuntimeError> {
+        let mut regexes = self.regexes.lock().unwrap();
+        if let Some(regex) = regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+        let regex = Regex::new(pattern).map_err(|e| RuntimeError {
+            msg: format!("Invalid regex {:?}: {}", pattern, e),
+        })?;
+        regexes.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
+}
+
+fn define_native<F>(environment: &mut Env, name: &str, f: F)
+where
+    F: FnMut(Reference<Env>, Vec<Value>) -> Result<Value, RuntimeError> + Send + Sync + 'static,
+{
+    environment.define(Symbol::from(name), Value::NativeClosure(reference::new(f)));
+}
+
+/// Define a native function which does not capture anything. Unlike
+/// [`define_native`] it may be called again while it runs, by the lisp
+/// functions it calls.
+fn define_function(
+    environment: &mut Env,
+    name: &str,
+    f: fn(Reference<Env>, Vec<Value>) -> Result<Value, RuntimeError>,
+) {
+    environment.define(Symbol::from(name), Value::NativeFunc(f));
+}
+
+fn string_arg<'a>(name: &str, args: &'a [Value], index: usize) -> Result<&'a String, RuntimeError> {
+    rust_lisp::utils::require_typed_arg::<&String>(name, args, index)
+}
+
+fn bool_value(b: bool) -> Value {
+    if b {
+        Value::True
+    } else {
+        Value::False
+    }
+}
+
+fn define_regex(environment: &mut Env, context: &Context) {
+    let c = context.clone();
+    define_native(environment, "re-match", move |_, args| {
+        let regex = c.regex(string_arg("re-match", &args, 0)?)?;
+        Ok(bool_value(
+            regex.is_match(string_arg("re-match", &args, 1)?),
+        ))
+    });
+    let c = context.clone();
+    define_native(environment, "re-find", move |_, args| {
+        let regex = c.regex(string_arg("re-find", &args, 0)?)?;
+        Ok(match regex.find(string_arg("re-find", &args, 1)?) {
+            Some(m) => Value::String(m.as_str().to_string()),
+            None => Value::False,
+        })
+    });
+    let c = context.clone();
+    define_native(environment, "re-captures", move |_, args| {
+        let regex = c.regex(string_arg("re-captures", &args, 0)?)?;
+        Ok(match regex.captures(string_arg("re-captures", &args, 1)?) {
+            Some(captures) => Value::List(
+                captures
+                    .iter()
+                    .map(|m| match m {
+                        Some(m) => Value::String(m.as_str().to_string()),
+                        None => Value::False,
+                    })
+                    .collect(),
+            ),
+            None => Value::False,
+        })
+    });
+    let c = context.clone();
+    define_native(environment, "re-replace", move |_, args| {
+        let regex = c.regex(string_arg("re-replace", &args, 0)?)?;
+        let text = string_arg("re-replace", &args, 1)?;
+        let replacement = string_arg("re-replace", &args, 2)?;
+        Ok(Value::String(
+            regex.replace_all(text, replacement.as_str()).to_string(),
+        ))
+    });
+}
+
+fn truthy(value: &Value) -> bool {
+    *value != Value::False && *value != Value::NIL
+}
+
+fn list_arg(name: &str, args: &[Value], index: usize) -> Result<Vec<Value>, RuntimeError> {
+    match rust_lisp::utils::require_arg(name, args, index)? {
+        Value::List(list) => Ok(list.into_iter().map(|v| Value::clone(&v)).collect()),
+        v => Err(RuntimeError {
+            msg: format!(
+                "Function \"{}\" requires argument {} to be a list, got {}",
+                name,
+                index + 1,
+                v
+            ),
+        }),
+    }
+}
+
+/// Entries of the hash map in argument `index`, sorted by key.
+fn hash_arg(name: &str, args: &[Value], index: usize) -> Result<Vec<(Value, Value)>, RuntimeError> {
+    match rust_lisp::utils::require_arg(name, args, index)? {
+        Value::HashMap(map) => {
+            let mut entries: Vec<(Value, Value)> = reference::borrow(map)
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            entries.sort_by_cached_key(|(k, _)| k.to_string());
+            Ok(entries)
+        }
+        v => Err(RuntimeError {
+            msg: format!(
+                "Function \"{}\" requires argument {} to be a hash map, got {}",
+                name,
+                index + 1,
+                v
+            ),
+        }),
+    }
+}
+
+/// Render `value` for use in an i3 command, strings without quotes.
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// The commands `value` stands for, either a single string or a list of
+/// strings.
+pub fn commands(value: &Value) -> Result<Vec<String>, String> {
+    let command = |v: &Value| match v {
+        Value::String(s) => Ok(s.clone()),
+        v => Err(format!("expected a command, got {}", v)),
+    };
+    match value {
+        Value::List(list) => list.into_iter().map(|v| command(&v)).collect(),
+        v => Ok(vec![command(v)?]),
+    }
+}
+
+/// Entries of the hash map `value`, sorted by key, or `None` if it is
+/// something else.
+pub fn entries(value: &Value) -> Option<Vec<(String, Value)>> {
+    let entries = hash_arg("entries", std::slice::from_ref(value), 0).ok()?;
+    Some(
+        entries
+            .into_iter()
+            .map(|(k, v)| (format_value(&k), v))
+            .collect(),
+    )
+}
+
+/// Call the function `f` with a single argument.
+fn apply(env: &Reference<Env>, f: &Value, arg: Value) -> Result<Value, RuntimeError> {
+    let quoted: List = vec![Value::Symbol(Symbol::from("quote")), arg]
+        .into_iter()
+        .collect();
+    let call: List = vec![f.clone(), Value::List(quoted)].into_iter().collect();
+    rust_lisp::interpreter::eval(env.clone(), &Value::List(call))
+}
+
+/// The first node of `tree`, depth first, for which `f` is true.
+fn find_node(env: &Reference<Env>, f: &Value, tree: &Value) -> Result<Option<Value>, RuntimeError> {
+    if truthy(&apply(env, f, tree.clone())?) {
+        return Ok(Some(tree.clone()));
+    }
+    for children in ["nodes", "floating_nodes"] {
+        if let Some(Value::List(list)) = walk(tree, &[Segment::Key(children.into())]) {
+            for child in list.into_iter() {
+                if let Some(node) = find_node(env, f, &Value::clone(&child))? {
+                    return Ok(Some(node));
                 }
-                Ok(rust_lisp::model::Value::True)
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The reply to `message_type`, read into the typed model and turned back
+/// into JSON for lisp.
+async fn state(
+    connection: &Connection,
+    message_type: MessageType,
+) -> Result<serde_json::Value, anyhow::Error> {
+    Ok(match message_type {
+        MessageType::Tree => serde_json::to_value(connection.get_tree().await?)?,
+        MessageType::Workspace => serde_json::to_value(connection.get_workspaces().await?)?,
+        MessageType::Outputs => serde_json::to_value(connection.get_outputs().await?)?,
+        MessageType::Marks => serde_json::to_value(connection.get_marks().await?)?,
+        MessageType::BindingState => serde_json::to_value(connection.get_binding_state().await?)?,
+        MessageType::GetInputs => serde_json::to_value(connection.get_inputs().await?)?,
+        MessageType::GetSeats => serde_json::to_value(connection.get_seats().await?)?,
+        t => return Err(anyhow::anyhow!("{:?} does not query the state", t)),
+    })
+}
+
+fn define_queries(environment: &mut Env, context: &Context) {
+    for (name, message_type) in [
+        ("i3-tree", MessageType::Tree),
+        ("i3-workspaces", MessageType::Workspace),
+        ("i3-outputs", MessageType::Outputs),
+        ("i3-marks", MessageType::Marks),
+        ("i3-binding-state", MessageType::BindingState),
+        ("i3-inputs", MessageType::GetInputs),
+        ("i3-seats", MessageType::GetSeats),
+    ] {
+        let c = context.clone();
+        define_native(environment, name, move |_, _| c.query(message_type));
+    }
+    let c = context.clone();
+    define_native(environment, "__tree__", move |_, _| {
+        c.query(MessageType::Tree)
+    });
+    // The predicate may call `find-node` again, so it can't capture the
+    // context and gets the tree through `__tree__` instead.
+    define_function(environment, "find-node", |e, args| {
+        let f = rust_lisp::utils::require_arg("find-node", &args, 0)?;
+        let query: List = std::iter::once(Value::Symbol(Symbol::from("__tree__"))).collect();
+        let tree = rust_lisp::interpreter::eval(e.clone(), &Value::List(query))?;
+        Ok(find_node(&e, f, &tree)?.unwrap_or(Value::False))
+    });
+}
+
+fn define_state(environment: &mut Env, context: &Context) {
+    let c = context.clone();
+    define_native(environment, "state-get", move |_, args| {
+        let key = string_arg("state-get", &args, 0)?;
+        Ok(match c.store.lock().unwrap().shared.get(key) {
+            Some(v) => serde_lisp_value(v),
+            None => args.get(1).cloned().unwrap_or(Value::False),
+        })
+    });
+    let c = context.clone();
+    define_native(environment, "state-set", move |_, args| {
+        let key = string_arg("state-set", &args, 0)?;
+        let value = rust_lisp::utils::require_arg("state-set", &args, 1)?;
+        let json = lisp_serde_value(value)?;
+        c.store.lock().unwrap().shared.insert(key.clone(), json);
+        Ok(value.clone())
+    });
+    let c = context.clone();
+    define_native(environment, "self-get", move |_, args| {
+        let key = string_arg("self-get", &args, 0)?;
+        Ok(
+            match c.store.lock().unwrap().program("self-get")?.get(key) {
+                Some(v) => serde_lisp_value(v),
+                None => args.get(1).cloned().unwrap_or(Value::False),
             },
-        )),
+        )
+    });
+    let c = context.clone();
+    define_native(environment, "self-set", move |_, args| {
+        let key = string_arg("self-set", &args, 0)?;
+        let value = rust_lisp::utils::require_arg("self-set", &args, 1)?;
+        let json = lisp_serde_value(value)?;
+        c.store
+            .lock()
+            .unwrap()
+            .program("self-set")?
+            .insert(key.clone(), json);
+        Ok(value.clone())
+    });
+}
+
+/// Make every function body of `value` account for its call, so
+/// evaluations can be stopped once they exhaust their budget.
+///
+/// The body of `(defun f (x) body...)` becomes
+/// `(__leave__ (__enter__) (begin body...))`, the same goes for `lambda`.
+fn instrument(value: &Value) -> Value {
+    let Value::List(list) = value else {
+        return value.clone();
+    };
+    let items: Vec<Value> = list.into_iter().map(|v| Value::clone(&v)).collect();
+    let params = match items.first() {
+        Some(Value::Symbol(s)) if s.to_string() == "quote" => return value.clone(),
+        Some(Value::Symbol(s)) if s.to_string() == "defun" => 3,
+        Some(Value::Symbol(s)) if s.to_string() == "lambda" => 2,
+        _ => return Value::List(items.iter().map(instrument).collect()),
+    };
+    if items.len() <= params {
+        return value.clone();
+    }
+    Value::List(
+        items[..params]
+            .iter()
+            .cloned()
+            .chain(std::iter::once(counted(
+                items[params..].iter().map(instrument),
+            )))
+            .collect(),
+    )
+}
+
+/// `(__leave__ (__enter__) (begin body...))`
+fn counted(body: impl Iterator<Item = Value>) -> Value {
+    let body: List = std::iter::once(Value::Symbol(Symbol::from("begin")))
+        .chain(body)
+        .collect();
+    let enter: List = std::iter::once(Value::Symbol(Symbol::from("__enter__"))).collect();
+    let call: List = [
+        Value::Symbol(Symbol::from("__leave__")),
+        Value::List(enter),
+        Value::List(body),
+    ]
+    .into_iter()
+    .collect();
+    Value::List(call)
+}
+
+fn define_budget(environment: &mut Env, context: &Context) {
+    let c = context.clone();
+    define_native(environment, "__enter__", move |_, _| {
+        c.usage.lock().unwrap().enter()?;
+        Ok(Value::NIL)
+    });
+    let c = context.clone();
+    define_native(environment, "__leave__", move |_, args| {
+        let mut usage = c.usage.lock().unwrap();
+        usage.depth = usage.depth.saturating_sub(1);
+        Ok(rust_lisp::utils::require_arg("__leave__", &args, 1)?.clone())
+    });
+}
+
+const WEEKDAYS: [&str; 7] = [
+    "sunday",
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+];
+
+fn system_error(name: &str, e: impl std::fmt::Display) -> RuntimeError {
+    RuntimeError {
+        msg: format!("Function \"{}\" failed: {}", name, e),
+    }
+}
+
+fn hostname() -> std::io::Result<String> {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for writes of its whole length, which is
+    // the length passed.
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let end = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    Ok(String::from_utf8_lossy(&buffer[..end]).into_owned())
+}
+
+/// Seconds since the epoch.
+fn now() -> Result<i64, RuntimeError> {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .map_err(|e| system_error("now", e))
+}
+
+/// Evaluate a lisp file in the environment `include` is called in,
+/// relative paths are resolved in `__directory__`.
+fn include(env: Reference<Env>, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    let name = string_arg("include", &args, 0)?;
+    let path = match reference::borrow(&env).get(&Symbol::from("__directory__")) {
+        Some(Value::String(directory)) => PathBuf::from(directory).join(name),
+        _ => PathBuf::from(name),
+    };
+    let error = |e: &dyn std::fmt::Display| RuntimeError {
+        msg: format!("Failed to include {}: {}", path.display(), e),
+    };
+    let source = std::fs::read_to_string(&path).map_err(|e| error(&e))?;
+    let program = crate::config::parse(&source).map_err(|e| error(&e))?;
+    // Counted like a function call, which stops files including each other.
+    rust_lisp::interpreter::eval(env, &counted(program.iter().map(instrument)))
+}
+
+/// Read only information about the machine, nothing is run.
+fn define_system(environment: &mut Env) {
+    define_native(environment, "getenv", |_, args| {
+        let name = string_arg("getenv", &args, 0)?;
+        Ok(match std::env::var(name) {
+            Ok(value) => Value::String(value),
+            Err(_) => args.get(1).cloned().unwrap_or(Value::False),
+        })
+    });
+    define_native(environment, "hostname", |_, _| {
+        Ok(Value::String(
+            hostname().map_err(|e| system_error("hostname", e))?,
+        ))
+    });
+    define_native(environment, "now", |_, _| {
+        Ok(serde_lisp_value(&now()?.into()))
+    });
+    define_native(environment, "weekday", |_, _| {
+        let time = now()? as libc::time_t;
+        // SAFETY: tm only consists of integers and a pointer, for which all
+        // zeroes are valid.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        // SAFETY: both pointers come from references to live values.
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return Err(system_error("weekday", std::io::Error::last_os_error()));
+        }
+        Ok(Value::String(WEEKDAYS[tm.tm_wday as usize].to_string()))
+    });
+    define_native(environment, "uptime", |_, _| {
+        let uptime =
+            std::fs::read_to_string("/proc/uptime").map_err(|e| system_error("uptime", e))?;
+        let seconds = uptime
+            .split_whitespace()
+            .next()
+            .and_then(|s| s.parse::<f64>().ok())
+            .ok_or_else(|| system_error("uptime", "unexpected /proc/uptime"))?;
+        Ok(serde_lisp_value(&(seconds as i64).into()))
+    });
+}
+
+fn define_strings(environment: &mut Env) {
+    define_native(environment, "starts-with", |_, args| {
+        let s = string_arg("starts-with", &args, 0)?;
+        Ok(bool_value(s.starts_with(
+            string_arg("starts-with", &args, 1)?.as_str(),
+        )))
+    });
+    define_native(environment, "ends-with", |_, args| {
+        let s = string_arg("ends-with", &args, 0)?;
+        Ok(bool_value(
+            s.ends_with(string_arg("ends-with", &args, 1)?.as_str()),
+        ))
+    });
+    define_native(environment, "contains", |_, args| {
+        let needle = rust_lisp::utils::require_arg("contains", &args, 1)?;
+        Ok(bool_value(match rust_lisp::utils::require_arg("contains", &args, 0)? {
+            Value::String(s) => s.contains(string_arg("contains", &args, 1)?.as_str()),
+            Value::List(_) => list_arg("contains", &args, 0)?.contains(needle),
+            Value::HashMap(map) => reference::borrow(map).contains_key(needle),
+            v => {
+                return Err(RuntimeError {
+                    msg: format!(
+                        "Function \"contains\" requires argument 1 to be a string, list or hash map, got {}",
+                        v
+                    ),
+                })
+            }
+        }))
+    });
+    define_native(environment, "lower", |_, args| {
+        Ok(Value::String(string_arg("lower", &args, 0)?.to_lowercase()))
+    });
+    define_native(environment, "upper", |_, args| {
+        Ok(Value::String(string_arg("upper", &args, 0)?.to_uppercase()))
+    });
+    define_native(environment, "trim", |_, args| {
+        Ok(Value::String(
+            string_arg("trim", &args, 0)?.trim().to_string(),
+        ))
+    });
+    define_native(environment, "split", |_, args| {
+        let s = string_arg("split", &args, 0)?;
+        let separator = string_arg("split", &args, 1)?;
+        Ok(Value::List(
+            s.split(separator.as_str())
+                .map(|p| Value::String(p.to_string()))
+                .collect(),
+        ))
+    });
+    define_native(environment, "join", |_, args| {
+        let parts: Vec<String> = list_arg("join", &args, 0)?
+            .into_iter()
+            .map(|v| match v {
+                Value::String(s) => s,
+                v => v.to_string(),
+            })
+            .collect();
+        Ok(Value::String(parts.join(string_arg("join", &args, 1)?)))
+    });
+}
+
+fn define_collections(environment: &mut Env) {
+    define_native(environment, "keys", |_, args| {
+        Ok(Value::List(
+            hash_arg("keys", &args, 0)?
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect(),
+        ))
+    });
+    define_native(environment, "values", |_, args| {
+        Ok(Value::List(
+            hash_arg("values", &args, 0)?
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect(),
+        ))
+    });
+    define_function(environment, "find", |e, args| {
+        let f = rust_lisp::utils::require_arg("find", &args, 0)?;
+        for item in list_arg("find", &args, 1)? {
+            if truthy(&apply(&e, f, item.clone())?) {
+                return Ok(item);
+            }
+        }
+        Ok(Value::False)
+    });
+    define_function(environment, "any", |e, args| {
+        let f = rust_lisp::utils::require_arg("any", &args, 0)?;
+        for item in list_arg("any", &args, 1)? {
+            if truthy(&apply(&e, f, item)?) {
+                return Ok(Value::True);
+            }
+        }
+        Ok(Value::False)
+    });
+    define_function(environment, "all", |e, args| {
+        let f = rust_lisp::utils::require_arg("all", &args, 0)?;
+        for item in list_arg("all", &args, 1)? {
+            if !truthy(&apply(&e, f, item)?) {
+                return Ok(Value::False);
+            }
+        }
+        Ok(Value::True)
+    });
+}
+
+fn env(context: &Context) -> Env {
+    let mut environment = rust_lisp::default_env();
+    environment.define(Symbol::from("__input__"), Value::NIL);
+    environment.define(
+        Symbol::from("__directory__"),
+        match &context.directory {
+            Some(directory) => Value::String(directory.to_string_lossy().into_owned()),
+            None => Value::NIL,
+        },
     );
+    define_native(&mut environment, "load", |e, args| {
+        match lookup("load", &e, &args, 1)? {
+            Some(v) => Ok(v),
+            None => Err(RuntimeError {
+                msg: format!("No such key {}", args[0]),
+            }),
+        }
+    });
+    define_native(&mut environment, "load-or", |e, args| {
+        let default = rust_lisp::utils::require_arg("load-or", &args, 1)?.clone();
+        Ok(lookup("load-or", &e, &args, 2)?.unwrap_or(default))
+    });
+    define_native(&mut environment, "has-key", |e, args| {
+        Ok(bool_value(lookup("has-key", &e, &args, 1)?.is_some()))
+    });
+    define_regex(&mut environment, context);
+    define_strings(&mut environment);
+    define_collections(&mut environment);
+    define_queries(&mut environment, context);
+    define_state(&mut environment, context);
+    define_budget(&mut environment, context);
+    define_system(&mut environment);
+    define_function(&mut environment, "include", include);
     environment
 }
+
+/// Functions for common matchers, evaluated before `init`.
+const PRELUDE: &str = include_str!("prelude.lisp");
+
+/// The environment `init` was evaluated in, shared by all evaluations.
+pub struct Interpreter {
+    env: Reference<Env>,
+    context: Context,
+}
+
+impl Interpreter {
+    pub fn new(context: Context, budget: Budget, init: Vec<Value>) -> Result<Self, RuntimeError> {
+        context.usage.lock().unwrap().budget = budget;
+        let interpreter = Self {
+            env: reference::new(env(&context)),
+            context,
+        };
+        let prelude = crate::config::parse(PRELUDE).expect("the prelude is valid");
+        interpreter.run(interpreter.env.clone(), prelude)?;
+        interpreter.run(interpreter.env.clone(), init)?;
+        Ok(interpreter)
+    }
+
+    fn run(&self, env: Reference<Env>, code: Vec<Value>) -> Result<Value, RuntimeError> {
+        {
+            let mut usage = self.context.usage.lock().unwrap();
+            usage.steps = 0;
+            usage.depth = 0;
+            usage.exhausted = false;
+        }
+        rust_lisp::interpreter::eval_block(env, code.iter().map(instrument))
+    }
+
+    /// Whether the last evaluation failed because it ran out of budget.
+    pub fn exhausted(&self) -> bool {
+        self.context.usage.lock().unwrap().exhausted
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Make `event` the input of the following evaluations.
+    ///
+    /// The input lives in the shared environment, so functions defined by
+    /// `init` see it as well.
+    pub fn set_input(&self, event: &serde_json::Value) {
+        self.context.next_event();
+        reference::borrow_mut(&self.env).define(Symbol::from("__input__"), serde_lisp_value(event));
+    }
+
+    /// Evaluate `code` in a scope of its own, its definitions are not
+    /// visible to other evaluations.
+    pub fn eval(&self, code: Vec<Value>) -> Result<Value, RuntimeError> {
+        self.eval_with(code, vec![])
+    }
+
+    /// Like [`Interpreter::eval`], with `bindings` defined in the scope.
+    pub fn eval_with(
+        &self,
+        code: Vec<Value>,
+        bindings: Vec<(&str, Value)>,
+    ) -> Result<Value, RuntimeError> {
+        let mut scope = Env::extend(self.env.clone());
+        for (name, value) in bindings {
+            scope.define(Symbol::from(name), value);
+        }
+        self.run(reference::new(scope), code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(code: &str) -> Vec<Value> {
+        rust_lisp::parser::parse(code).map(|v| v.unwrap()).collect()
+    }
+
+    fn eval(input: serde_json::Value, code: &str) -> Result<Value, RuntimeError> {
+        let interpreter = Interpreter::new(Context::default(), Budget::default(), vec![]).unwrap();
+        interpreter.set_input(&input);
+        interpreter.eval(parse(code))
+    }
+
+    #[test]
+    fn find_node_may_be_called_by_its_predicate() {
+        let interpreter = Interpreter::new(Context::default(), Budget::default(), vec![]).unwrap();
+        interpreter.set_input(&json!({}));
+        interpreter.context().replies.lock().unwrap().insert(
+            MessageType::Tree,
+            crate::mock::tree(vec![crate::mock::sway_window(6, "firefox")]),
+        );
+        let code = r#"(load ".id" (find-node (lambda (n)
+            (and (== (load-or ".type" F n) "con") (== (workspace-of (load ".id" n)) "1")))))"#;
+        assert_eq!(
+            interpreter.eval(parse(code)).unwrap(),
+            serde_lisp_value(&json!(6))
+        );
+    }
+
+    #[test]
+    fn paths_are_parsed() {
+        assert_eq!(
+            parse_path(r#".container.nodes[0]["a.b"]."c.d"[-1][*]"#).unwrap(),
+            vec![
+                Segment::Key("container".into()),
+                Segment::Key("nodes".into()),
+                Segment::Index(0),
+                Segment::Key("a.b".into()),
+                Segment::Key("c.d".into()),
+                Segment::Index(-1),
+                Segment::Wildcard,
+            ]
+        );
+        assert_eq!(parse_path(".").unwrap(), vec![]);
+        assert!(parse_path(".nodes[x]").is_err());
+        assert!(parse_path(".nodes[0").is_err());
+        assert!(parse_path(r#"."open"#).is_err());
+    }
+
+    #[test]
+    fn load_walks_lists_and_maps() {
+        let input = json!({
+            "container": {
+                "marks": ["a", "b"],
+                "nodes": [{"name": "x"}, {"id": 1}, {"name": "z"}],
+            }
+        });
+        let load = |code: &str| eval(input.clone(), code).unwrap().to_string();
+        assert_eq!(load(r#"(load ".container.nodes[0].name")"#), r#""x""#);
+        assert_eq!(load(r#"(load ".container.marks[-1]")"#), r#""b""#);
+        assert_eq!(load(r#"(load ".container.nodes[*].name")"#), r#"("x" "z")"#);
+        assert_eq!(load(r#"(load-or ".container.urgent" F)"#), "F");
+        assert_eq!(load(r#"(has-key ".container.marks[2]")"#), "F");
+        assert_eq!(load(r#"(has-key ".container.marks[1]")"#), "T");
+        assert!(eval(input.clone(), r#"(load ".container.urgent")"#).is_err());
+        assert!(eval(input, r#"(has-key ".container[")"#).is_err());
+    }
+
+    #[test]
+    fn regexes_match_and_capture() {
+        let input = json!({"container": {"name": "Inbox - Mozilla Thunderbird"}});
+        let run = |code: &str| eval(input.clone(), code).unwrap().to_string();
+        assert_eq!(
+            run(r#"(re-match "^Inbox - .* Thunderbird$" (load ".container.name"))"#),
+            "T"
+        );
+        assert_eq!(run(r#"(re-match "^Slack" (load ".container.name"))"#), "F");
+        assert_eq!(
+            run(r#"(re-find "[A-Z][a-z]+$" (load ".container.name"))"#),
+            r#""Thunderbird""#
+        );
+        assert_eq!(
+            run(r#"(re-captures "^(.*) - (Mozilla)?(x)?" (load ".container.name"))"#),
+            r#"("Inbox - Mozilla" "Inbox" "Mozilla" F)"#
+        );
+        assert_eq!(
+            run(r#"(re-replace "Mozilla " (load ".container.name") "")"#),
+            r#""Inbox - Thunderbird""#
+        );
+        assert!(eval(input, r#"(re-match "(" "")"#).is_err());
+    }
+
+    #[test]
+    fn strings_and_collections() {
+        let input = json!({
+            "container": {
+                "name": " Inbox - Thunderbird ",
+                "marks": ["mail", "chat"],
+                "window_properties": {"class": "thunderbird", "instance": "Mail"},
+            }
+        });
+        let run = |code: &str| eval(input.clone(), code).unwrap().to_string();
+        assert_eq!(run(r#"(starts-with "thunderbird" "thunder")"#), "T");
+        assert_eq!(run(r#"(ends-with "thunderbird" "thunder")"#), "F");
+        assert_eq!(run(r#"(contains "thunderbird" "derb")"#), "T");
+        assert_eq!(run(r#"(contains (load ".container.marks") "chat")"#), "T");
+        assert_eq!(
+            run(r#"(contains (load ".container.window_properties") "role")"#),
+            "F"
+        );
+        assert_eq!(
+            run(r#"(upper (trim (load ".container.name")))"#),
+            r#""INBOX - THUNDERBIRD""#
+        );
+        assert_eq!(run(r#"(lower "Mail")"#), r#""mail""#);
+        assert_eq!(
+            run(r#"(split (trim (load ".container.name")) " - ")"#),
+            r#"("Inbox" "Thunderbird")"#
+        );
+        assert_eq!(run(r#"(join (list "a" 1 "b") ",")"#), r#""a,1,b""#);
+        assert_eq!(
+            run(r#"(keys (load ".container.window_properties"))"#),
+            r#"("class" "instance")"#
+        );
+        assert_eq!(
+            run(r#"(values (load ".container.window_properties"))"#),
+            r#"("thunderbird" "Mail")"#
+        );
+        assert_eq!(
+            run(r#"(find (lambda (m) (starts-with m "c")) (load ".container.marks"))"#),
+            r#""chat""#
+        );
+        assert_eq!(
+            run(r#"(any (lambda (m) (== m "mail")) (load ".container.marks"))"#),
+            "T"
+        );
+        assert_eq!(
+            run(r#"(all (lambda (m) (== m "mail")) (load ".container.marks"))"#),
+            "F"
+        );
+        let error = eval(input.clone(), r#"(lower (load ".container.marks"))"#).unwrap_err();
+        assert!(error.msg.contains("lower"), "{}", error.msg);
+        let error = eval(input, r#"(keys "x")"#).unwrap_err();
+        assert!(error.msg.contains("hash map"), "{}", error.msg);
+    }
+
+    #[test]
+    fn state_outlives_environments() {
+        let interpreter = Interpreter::new(Context::default(), Budget::default(), vec![]).unwrap();
+        let context = interpreter.context();
+        let run = |code: &str| interpreter.eval(parse(code)).map(|v| v.to_string());
+        assert_eq!(run(r#"(state-get "count" 0)"#).unwrap(), "0");
+        run(r#"(state-set "count" (+ 1 (state-get "count" 0)))"#).unwrap();
+        run(r#"(state-set "count" (+ 1 (state-get "count" 0)))"#).unwrap();
+        assert_eq!(run(r#"(state-get "count")"#).unwrap(), "2");
+        assert!(run(r#"(state-set "f" (lambda (x) x))"#).is_err());
+
+        assert!(run(r#"(self-get "first")"#).is_err());
+        context.set_program(Some("a"));
+        run(r#"(self-set "first" (list 1 "x"))"#).unwrap();
+        assert_eq!(run(r#"(self-get "first")"#).unwrap(), r#"(1 "x")"#);
+        context.set_program(Some("b"));
+        assert_eq!(run(r#"(self-get "first")"#).unwrap(), "F");
+    }
+
+    #[test]
+    fn init_is_shared_and_scopes_are_not() {
+        let init = parse(r#"(defun name () (load ".container.name"))"#);
+        let interpreter = Interpreter::new(Context::default(), Budget::default(), init).unwrap();
+        interpreter.set_input(&json!({"container": {"name": "a"}}));
+        assert_eq!(
+            interpreter
+                .eval(parse("(define x 1) (name)"))
+                .unwrap()
+                .to_string(),
+            r#""a""#
+        );
+        interpreter.set_input(&json!({"container": {"name": "b"}}));
+        assert_eq!(
+            interpreter.eval(parse("(name)")).unwrap().to_string(),
+            r#""b""#
+        );
+        assert!(interpreter.eval(parse("x")).is_err());
+    }
+
+    #[test]
+    fn evaluations_are_limited() {
+        let init = parse(
+            r#"
+            (defun forever (n) (forever (+ n 1)))
+            (defun fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+            "#,
+        );
+        let budget = Budget {
+            steps: 1000,
+            depth: 50,
+        };
+        let interpreter = Interpreter::new(Context::default(), budget, init).unwrap();
+        let error = interpreter.eval(parse("(forever 0)")).unwrap_err();
+        assert_eq!(error.msg, "Evaluation exceeded a depth of 50");
+        assert!(interpreter.exhausted());
+        let error = interpreter.eval(parse("(fib 20)")).unwrap_err();
+        assert_eq!(error.msg, "Evaluation exceeded 1000 steps");
+        assert_eq!(
+            interpreter.eval(parse("(fib 10)")).unwrap().to_string(),
+            "55"
+        );
+        assert!(!interpreter.exhausted());
+        assert_eq!(
+            interpreter
+                .eval(parse("((lambda (x) (fib x)) 5)"))
+                .unwrap()
+                .to_string(),
+            "5"
+        );
+        assert!(interpreter
+            .eval(parse("(define f (lambda () (f))) (f)"))
+            .is_err());
+        assert!(interpreter.exhausted());
+    }
+
+    #[test]
+    fn wide_integers_are_kept() {
+        let id = 94_558_734_861_344_i64;
+        let input = json!({"container": {"id": id, "window": 8, "big": u64::MAX}});
+        let value = eval(input.clone(), r#"(load ".container.id")"#).unwrap();
+        assert_eq!(format_value(&value), id.to_string());
+        let value = eval(input.clone(), r#"(load ".container.big")"#).unwrap();
+        assert_eq!(format_value(&value), u64::MAX.to_string());
+        let value = eval(input, r#"(+ (load ".container.window") 1)"#).unwrap();
+        assert_eq!(format_value(&value), "9");
+    }
+
+    #[test]
+    fn system_information() {
+        let string = |code: &str| match eval(json!({}), code).unwrap() {
+            Value::String(s) => s,
+            v => panic!("{} returned {}", code, v),
+        };
+        std::env::set_var("I3TOOLWAIT_TEST_GETENV", "wayland");
+        assert_eq!(string(r#"(getenv "I3TOOLWAIT_TEST_GETENV")"#), "wayland");
+        assert_eq!(string(r#"(getenv "I3TOOLWAIT_TEST_UNSET" "x11")"#), "x11");
+        assert_eq!(
+            eval(json!({}), r#"(getenv "I3TOOLWAIT_TEST_UNSET")"#).unwrap(),
+            Value::False
+        );
+        assert!(!string("(hostname)").is_empty());
+        assert!(WEEKDAYS.contains(&string("(weekday)").as_str()));
+        let now = format_value(&eval(json!({}), "(now)").unwrap());
+        assert!(now.parse::<i64>().unwrap().abs_diff(super::now().unwrap()) < 5);
+        if std::path::Path::new("/proc/uptime").exists() {
+            assert!(matches!(
+                eval(json!({}), "(uptime)").unwrap(),
+                Value::Int(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn prelude_describes_windows() {
+        let interpreter = Interpreter::new(Context::default(), Budget::default(), vec![]).unwrap();
+        let eval = |code: &str| format_value(&interpreter.eval(parse(code)).unwrap());
+        let mut window = crate::mock::i3_window(94_558_734_861_344, "LibreWolf");
+        window["window_properties"]["title"] = "Mozilla LibreWolf".into();
+        interpreter.set_input(&json!({"change": "new", "container": window}));
+        assert_eq!(eval("(app)"), "LibreWolf");
+        assert_eq!(eval("(is-new)"), "T");
+        assert_eq!(eval("(con-id)"), "94558734861344");
+        assert_eq!(eval("(title)"), "Mozilla LibreWolf");
+        let window = crate::mock::sway_window(7, "foot");
+        interpreter.set_input(&json!({"change": "focus", "container": window}));
+        assert_eq!(eval("(app)"), "foot");
+        assert_eq!(eval("(is-new)"), "F");
+        assert_eq!(eval("(title)"), "foot");
+    }
+
+    #[test]
+    fn files_are_included_relative_to_the_directory() {
+        let directory =
+            std::env::temp_dir().join(format!("i3toolwait-include-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("lib")).unwrap();
+        std::fs::write(
+            directory.join("lib/apps.lisp"),
+            "; shared between machines\n(include \"lib/names.lisp\")\n(defun is-browser () (contains browsers (app)))",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("lib/names.lisp"),
+            "(define browsers (list \"firefox\"))",
+        )
+        .unwrap();
+        std::fs::write(directory.join("loop.lisp"), "(include \"loop.lisp\")").unwrap();
+        std::fs::write(directory.join("broken.lisp"), "(defun f ()").unwrap();
+        let context = Context::default().in_directory(Some(directory.clone()));
+        let init = parse(r#"(include "lib/apps.lisp")"#);
+        let interpreter = Interpreter::new(context, Budget::default(), init).unwrap();
+
+        interpreter.set_input(&json!({"container": crate::mock::sway_window(1, "firefox")}));
+        assert_eq!(
+            interpreter.eval(parse("(is-browser)")).unwrap(),
+            Value::True
+        );
+        let error = interpreter
+            .eval(parse(r#"(include "loop.lisp")"#))
+            .unwrap_err();
+        assert_eq!(error.msg, "Evaluation exceeded a depth of 100");
+        let error = interpreter
+            .eval(parse(r#"(include "broken.lisp")"#))
+            .unwrap_err();
+        assert_eq!(
+            error.msg,
+            format!(
+                "Failed to include {}: line 1, column 1: unclosed (: (defun f ()",
+                directory.join("broken.lisp").display()
+            )
+        );
+        assert!(interpreter
+            .eval(parse(r#"(include "missing.lisp")"#))
+            .is_err());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,29 +1,54 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use futures::StreamExt;
 use log::{debug, info, warn};
+use rust_lisp::model::Value as LispValue;
 use tokio::io::AsyncReadExt;
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout, timeout_at, Duration, Instant};
 
 mod config;
+mod criteria;
 mod i3ipc;
 mod lisp;
+#[cfg(test)]
+mod mock;
+mod repl;
 
-use config::{Config, ProgramEntry};
-use i3ipc::{Connection, MessageType};
+use config::{Cmd, Config, Program, ProgramEntry};
+use i3ipc::{
+    CommandOutcome, Connection, Event, EventStream, MessageType, ShutdownChange, ShutdownEvent,
+    WindowEvent,
+};
 
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(short, long, value_name = "FILE")]
     config: Option<PathBuf>,
+    /// IPC socket to use instead of looking for one
+    #[arg(short, long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+    /// Write every IPC message to FILE as JSON lines
+    #[arg(short, long, value_name = "FILE")]
+    transcript: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Evaluate lisp expressions against live window events
+    Repl,
 }
 
 impl Args {
-    fn finish(&mut self) {
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
         // TODO maybe return separate type
         if self.config.is_none() {
             self.config = Some(
@@ -32,110 +57,364 @@ impl Args {
                     .get_config_file("config.yaml"),
             );
         }
+        if self.config.as_deref() == Some(Path::new("-"))
+            && matches!(self.command, Some(Command::Repl))
+        {
+            return Err(anyhow!(
+                "The repl reads expressions from stdin, the config can't be read from it too"
+            ));
+        }
+        Ok(())
     }
 }
 
-fn new_window_cb(
-    _b: MessageType,
-    c: serde_json::Value,
-    config: &Config,
-    _args: &Args,
-    programs: &std::sync::Arc<tokio::sync::Mutex<Vec<ProgramEntry>>>,
-    tx: &tokio::sync::broadcast::Sender<()>,
-) -> futures::future::BoxFuture<'static, Vec<(MessageType, Vec<u8>)>> {
-    let config_ = config.clone();
-    let tx_ = tx.clone();
-    let programs_ = programs.clone();
-    Box::pin(async move {
-        let mut command = None;
-        let mut index = None;
-        debug!("Received window event: {}", &c);
-        for (i, p) in programs_.lock().await.iter().enumerate() {
-            match p {
-                ProgramEntry::Program(p) => {
-                    debug!("Evaluating program: {}", &p.match_);
-                    let e = lisp::env(&c);
-                    let init: Vec<rust_lisp::model::Value> = config_.init.clone().into();
-                    let prog: Vec<rust_lisp::model::Value> = p.match_.clone().into();
-                    let m = init.into_iter().chain(prog.into_iter());
-                    let result =
-                        rust_lisp::interpreter::eval_block(rust_lisp::model::reference::new(e), m);
-                    if let Ok(v) = &result {
-                        debug!("Received result: {}", v);
-                        if *v == rust_lisp::model::Value::False {
-                            continue;
-                        }
-                        debug!("Match found");
-                        let mut vars = HashMap::with_capacity(1);
-                        vars.insert("result".to_string(), v.to_string());
-                        let cmd = strfmt::strfmt(&p.cmd, &vars).unwrap();
-                        debug!("Command: {}", &cmd);
-
-                        index = Some(i);
-                        command = Some(cmd);
-                        break;
-                    } else {
-                        warn!("Program produced an error: {:?}", &result);
-                    }
-                }
-                _ => {
-                    // Ignore signal entries
-                    ()
-                }
-            };
+/// Find the first pending program matching the window event, remove it
+/// and return it together with the commands it produced.
+///
+/// Programs whose matcher exceeds the evaluation budget, or whose commands
+/// can not be produced, are removed and recorded as failed.
+fn match_window(
+    event: &WindowEvent,
+    failures: &Failures,
+    interpreter: &lisp::Interpreter,
+    programs: &mut Vec<Program>,
+) -> Option<(Program, Vec<String>)> {
+    let c = serde_json::to_value(event).unwrap();
+    debug!("Received window event: {}", &c);
+    interpreter.set_input(&c);
+    let mut i = 0;
+    while i < programs.len() {
+        let p = &programs[i];
+        let result = match &p.criteria {
+            Some(criteria) => {
+                debug!("Checking criteria: {}", criteria);
+                Ok(
+                    (event.change == p.change && criteria.matches(&event.container))
+                        .then(|| lisp::serde_lisp_value(&event.container.id.into())),
+                )
+            }
+            None => evaluate(interpreter, p),
+        };
+        let commands = match result {
+            Ok(None) => {
+                i += 1;
+                continue;
+            }
+            Ok(Some(result)) => {
+                debug!("Match found");
+                commands(interpreter, p, result).map_err(|e| ("cmd", e))
+            }
+            Err(e) => Err(("match", e)),
+        };
+        let p = programs.remove(i);
+        match commands {
+            Ok(commands) => {
+                debug!("Commands: {:?}", &commands);
+                return Some((p, commands));
+            }
+            Err((part, e)) => {
+                let failure = Failure {
+                    origin: format!("program {}", p.name.as_deref().unwrap_or_default()),
+                    reason: Reason::Evaluation {
+                        part: part.to_string(),
+                        error: e,
+                    },
+                };
+                warn!("{}", failure);
+                failures.lock().unwrap().push(failure);
+            }
+        }
+    }
+    debug!("No match found");
+    None
+}
+
+/// Evaluate the lisp matcher of `program`, returns the result unless the
+/// window did not match, or an error if the matcher exceeded its budget.
+fn evaluate(
+    interpreter: &lisp::Interpreter,
+    program: &Program,
+) -> Result<Option<LispValue>, String> {
+    let Some(match_) = program.match_.as_ref() else {
+        return Ok(None);
+    };
+    debug!("Evaluating program: {}", match_);
+    interpreter.context().set_program(program.name.as_deref());
+    let start = Instant::now();
+    let result = interpreter.eval(match_.clone().into());
+    debug!(
+        "Evaluated program {} in {:?}",
+        program.name.as_deref().unwrap_or_default(),
+        start.elapsed()
+    );
+    match result {
+        Ok(v) => {
+            debug!("Received result: {}", v);
+            Ok((v != LispValue::False).then_some(v))
         }
-        if let Some(index) = index {
-            let mut plock = programs_.lock().await;
-            plock.remove(index);
-            if plock.len() == 0 {
-                tx_.send(()).unwrap();
+        Err(e) if interpreter.exhausted() => Err(e.msg),
+        Err(e) => {
+            warn!("Program produced an error: {:?}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// The commands `program` runs for the match `result`.
+///
+/// A lisp `cmd` decides itself, a format `cmd` gets `{result}` replaced,
+/// or the keys of a hash map by their values. Only without `cmd` the
+/// matcher returns the commands, as a list or under `cmd` of a hash map
+/// whose other entries are replaced in them.
+fn commands(
+    interpreter: &lisp::Interpreter,
+    program: &Program,
+    result: LispValue,
+) -> Result<Vec<String>, String> {
+    let mut vars = HashMap::new();
+    let commands = match (&program.cmd, lisp::entries(&result)) {
+        (Some(Cmd::Eval { eval }), _) => {
+            interpreter.context().set_program(program.name.as_deref());
+            let value = interpreter
+                .eval_with(eval.clone().into(), vec![("result", result)])
+                .map_err(|e| e.msg)?;
+            return lisp::commands(&value);
+        }
+        (Some(Cmd::Format(command)), Some(entries)) => {
+            for (key, value) in entries {
+                vars.insert(key, lisp::format_value(&value));
+            }
+            vec![command.clone()]
+        }
+        (Some(Cmd::Format(command)), None) => {
+            vars.insert("result".to_string(), lisp::format_value(&result));
+            vec![command.clone()]
+        }
+        (None, Some(entries)) => {
+            let mut commands = None;
+            for (key, value) in entries {
+                if key == "cmd" {
+                    commands = Some(lisp::commands(&value)?);
+                } else {
+                    vars.insert(key, lisp::format_value(&value));
+                }
             }
-            return vec![(MessageType::Command, command.unwrap().into_bytes())];
+            commands.ok_or("no cmd to run")?
         }
-        debug!("No match found");
-        Vec::new()
-    })
+        (None, None) => match result {
+            LispValue::List(_) => return lisp::commands(&result),
+            _ => return Err("no cmd to run".to_string()),
+        },
+    };
+    commands
+        .iter()
+        .map(|c| strfmt::strfmt(c, &vars).map_err(|e| format!("{}: {}", c, e)))
+        .collect()
 }
 
-async fn run_command<'a>(
-    connection: &mut Connection<'a>,
-    command: &str,
+/// Handle window events until every program has been matched or no
+/// event arrived before `deadline`.
+///
+/// Takes `events` so the subscription ends with the wait, events are not
+/// queued for a stream nobody reads anymore.
+///
+/// Only waiting for events is limited by the deadline. A command dropped
+/// while waiting for its reply would leave the reply to be taken by the
+/// next command on the connection.
+async fn wait_for_windows(
+    connection: &Connection,
+    failures: &Failures,
+    socket: Option<&Path>,
+    deadline: Instant,
+    mut events: EventStream,
+    interpreter: &lisp::Interpreter,
+    programs: &mut Vec<Program>,
 ) -> Result<(), anyhow::Error> {
-    let (_, responses) = connection
-        .communicate(&MessageType::Command, command.as_bytes())
-        .await?;
-    match responses {
-        serde_json::Value::Array(responses) => {
-            for response in responses {
-                if let serde_json::Value::Bool(v) = response.get("success").unwrap() {
-                    if !v {
-                        warn!("Failed to run command {}: {}", command, response);
+    while !programs.is_empty() {
+        let Ok(event) = timeout_at(deadline, events.next()).await else {
+            debug!("Timed out waiting for windows");
+            return Ok(());
+        };
+        let event = event.ok_or_else(|| anyhow!("Connection closed"))?;
+        match event {
+            Event::Window(event) => {
+                if let Some((program, commands)) =
+                    match_window(&event, failures, interpreter, programs)
+                {
+                    let name = program.name.as_deref().unwrap_or_default();
+                    let origin = format!("program {}", name);
+                    for command in &commands {
+                        let outcomes = run_command(connection, failures, &origin, command).await?;
+                        debug!("{}: {} -> {:?}", origin, command, outcomes);
                     }
+                    announce(connection, &format!("i3toolwait:matched:{}", name)).await;
                 }
             }
+            Event::Shutdown(ShutdownEvent {
+                change: ShutdownChange::Restart,
+            }) => {
+                info!("Window manager is restarting, reconnecting");
+                connection.reconnect(socket).await?;
+                if let Some(queries) = interpreter.context().connection() {
+                    queries.reconnect(socket).await?;
+                }
+                info!("Reconnected, waiting for {} programs", programs.len());
+            }
+            Event::Shutdown(ShutdownEvent {
+                change: ShutdownChange::Exit,
+            }) => {
+                return Err(anyhow!(
+                    "Window manager exited while waiting for {} programs",
+                    programs.len()
+                ));
+            }
+            _ => (),
         }
-        _ => panic!("invalid response"),
-    };
+    }
     Ok(())
 }
 
-async fn run<'a>(connection: &mut Connection<'a>, config: &Config) -> Result<(), anyhow::Error> {
-    let (_, resp) = connection.communicate(&MessageType::Version, b"").await?;
-    info!("i3 version is {}", resp.get("human_readable").unwrap());
+/// Tell other tools about the progress with a tick, nothing depends on it.
+async fn announce(connection: &Connection, payload: &str) {
+    if let Err(e) = connection.send_tick(payload).await {
+        warn!("Failed to send tick {}: {}", payload, e);
+    }
+}
+
+fn report_unmatched(programs: &[Program]) {
+    if !programs.is_empty() {
+        warn!("Not all programs consumed: {:?}", programs);
+        info!("Maybe the timouts are too short?");
+    }
+}
+
+/// Something a configuration entry asked for which did not happen.
+#[derive(Debug)]
+struct Failure {
+    /// The configuration entry that failed.
+    origin: String,
+    reason: Reason,
+}
+
+#[derive(Debug)]
+enum Reason {
+    /// The window manager did not run `command` successfully.
+    Command {
+        command: String,
+        outcome: CommandOutcome,
+    },
+    /// The lisp program `part` of the entry could not be evaluated.
+    Evaluation { part: String, error: String },
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match &self.reason {
+            Reason::Command { command, outcome } => {
+                let reason = outcome.error.as_deref().unwrap_or("unknown error");
+                if outcome.parse_error {
+                    write!(
+                        f,
+                        "{}: could not parse {}: {}",
+                        self.origin, command, reason
+                    )
+                } else {
+                    write!(f, "{}: {} failed: {}", self.origin, command, reason)
+                }
+            }
+            Reason::Evaluation { part, error } => {
+                write!(f, "{}: could not evaluate {}: {}", self.origin, part, error)
+            }
+        }
+    }
+}
+
+type Failures = std::sync::Mutex<Vec<Failure>>;
+
+async fn run_command(
+    connection: &Connection,
+    failures: &Failures,
+    origin: &str,
+    command: &str,
+) -> Result<Vec<CommandOutcome>, anyhow::Error> {
+    let outcomes = connection.run_command(command).await?;
+    for outcome in outcomes.iter().filter(|o| !o.success) {
+        let failure = Failure {
+            origin: origin.to_string(),
+            reason: Reason::Command {
+                command: command.to_string(),
+                outcome: outcome.clone(),
+            },
+        };
+        warn!("{}", failure);
+        failures.lock().unwrap().push(failure);
+    }
+    Ok(outcomes)
+}
+
+/// Wait for a tick with `payload`, returns false if the connection closed first.
+async fn wait_for_tick(ticks: &mut EventStream, payload: &str) -> bool {
+    while let Some(event) = ticks.next().await {
+        if let Event::Tick(tick) = event {
+            if !tick.first && tick.payload == payload {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// The name of the entry at `index` of `programs`, used in ticks and logs.
+fn entry_name(index: usize, entry: &ProgramEntry) -> String {
+    match entry {
+        ProgramEntry::Program(Program {
+            name: Some(name), ..
+        }) => name.clone(),
+        _ => index.to_string(),
+    }
+}
+
+async fn run(
+    connection: &Connection,
+    failures: &Failures,
+    ticks: &mut EventStream,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let version = connection.get_version().await?;
+    info!("i3 version is {}", version.human_readable);
 
     let mut signal_stream =
         tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
 
-    for p in config.programs.iter() {
+    for (i, p) in config.programs.iter().enumerate() {
+        let origin = format!("program {}", entry_name(i, p));
         match p {
             ProgramEntry::Program(p) => {
                 if let Some(r) = &p.run {
-                    run_command(connection, r).await?;
+                    run_command(connection, failures, &origin, r).await?;
+                }
+            }
+            ProgramEntry::Tick(p) => {
+                if let Some(r) = &p.run {
+                    run_command(connection, failures, &origin, r).await?;
+                }
+                match timeout(
+                    Duration::from_millis(p.timeout),
+                    wait_for_tick(ticks, &p.tick),
+                )
+                .await
+                {
+                    Ok(true) => debug!("Received tick {}", p.tick),
+                    Ok(false) => return Err(anyhow!("Connection closed")),
+                    Err(_) => warn!(
+                        "Ran into timeout when waiting for tick {}, program: {:?}",
+                        p.tick, p.run
+                    ),
                 }
             }
             ProgramEntry::Signal(p) => {
                 if let Some(r) = &p.run {
-                    run_command(connection, r).await?;
+                    run_command(connection, failures, &origin, r).await?;
                 }
                 if let Err(_) =
                     timeout(Duration::from_millis(p.timeout), signal_stream.recv()).await
@@ -151,6 +430,79 @@ async fn run<'a>(connection: &mut Connection<'a>, config: &Config) -> Result<(),
     Ok(())
 }
 
+/// Launch all programs of `config`, place their windows and run the final command.
+async fn execute(
+    connection: &Connection,
+    socket: Option<&Path>,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let mut programs: Vec<Program> = config
+        .programs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entry)| match entry {
+            ProgramEntry::Program(p) => {
+                let mut p = p.clone();
+                p.name = Some(entry_name(i, entry));
+                Some(p)
+            }
+            _ => None,
+        })
+        .collect();
+
+    let context = lisp::Context::with_connection(Arc::new(connection.duplicate()?))
+        .in_directory(config.directory.clone());
+    let start = Instant::now();
+    let interpreter = lisp::Interpreter::new(context, config.budget, config.init.clone().into())
+        .map_err(|e| anyhow!("Failed to evaluate init: {}", e))?;
+    debug!("Evaluated init in {:?}", start.elapsed());
+
+    let events = connection
+        .subscribe_with(
+            &[MessageType::SubWindow, MessageType::SubShutdown],
+            config.backlog,
+        )
+        .await?;
+    let mut ticks = connection
+        .subscribe_with(&[MessageType::SubTick], config.backlog)
+        .await?;
+    announce(connection, "i3toolwait:started").await;
+
+    let failures = Failures::default();
+    let deadline = Instant::now() + Duration::from_millis(config.timeout);
+    let (waited, ran) = tokio::join!(
+        wait_for_windows(
+            connection,
+            &failures,
+            socket,
+            deadline,
+            events,
+            &interpreter,
+            &mut programs
+        ),
+        run(connection, &failures, &mut ticks, config),
+    );
+    report_unmatched(&programs);
+    waited?;
+    ran?;
+
+    if let Some(cmd) = &config.cmd {
+        run_command(connection, &failures, "final cmd", cmd).await?;
+    }
+    announce(connection, "i3toolwait:done").await;
+
+    let failures = failures.into_inner().unwrap();
+    if config.strict && !failures.is_empty() {
+        let failures: Vec<String> = failures.iter().map(|f| f.to_string()).collect();
+        return Err(anyhow!(
+            "{} failures:\n  {}",
+            failures.len(),
+            failures.join("\n  ")
+        ));
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init_from_env(
@@ -160,56 +512,802 @@ async fn main() -> Result<()> {
     );
 
     let mut args = Args::parse();
-    args.finish();
-    let args = std::sync::Arc::new(args);
+    args.finish()?;
     let mut config = String::new();
     if args.config.as_ref().unwrap() == &PathBuf::from_str("-").unwrap() {
         tokio::io::stdin().read_to_string(&mut config).await?;
     } else {
         tokio::fs::File::open(args.config.as_ref().unwrap())
-            .await.with_context(
-                || format!("Failed to read config file {}", args.config.as_ref().unwrap().to_string_lossy())
-            )?
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to read config file {}",
+                    args.config.as_ref().unwrap().to_string_lossy()
+                )
+            })?
             .read_to_string(&mut config)
             .await?;
     }
-    let config: Config = serde_yaml::from_str(&config)?;
-    let config = std::sync::Arc::new(config);
-    let programs = std::sync::Arc::new(tokio::sync::Mutex::new(config.programs.clone()));
+    let mut config: Config = serde_yaml::from_str(&config)?;
+    config.validate()?;
+    if args.config.as_ref().unwrap() != &PathBuf::from_str("-").unwrap() {
+        config.directory = args
+            .config
+            .as_ref()
+            .unwrap()
+            .parent()
+            .map(Path::to_path_buf);
+    }
+
+    let socket = args.socket.as_deref();
+    let transcript = args
+        .transcript
+        .as_deref()
+        .map(i3ipc::Transcript::create)
+        .transpose()?;
+    let connection =
+        Connection::connect_with_transcript(&i3ipc::get_socket_path(socket).await?, transcript)?;
+    match args.command {
+        Some(Command::Repl) => {
+            let input = tokio::io::BufReader::new(tokio::io::stdin());
+            repl::repl(&connection, socket, &config, input, tokio::io::stdout()).await
+        }
+        None => execute(&connection, socket, &config).await,
+    }
+}
 
-    let mut connection = Connection::connect((i3ipc::get_socket_path().await?).as_ref())?;
-    let mut sub_connection = connection.clone();
-    let cb_config = config.clone();
-    let cb_args = args.clone();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{i3_window, sway_window, window_event, MockServer};
 
-    let (tx, mut rx) = tokio::sync::broadcast::channel::<()>(1);
+    const INIT: &str = r#"
+init: |
+  (begin
+    (define i3_path ".container.window_properties.class")
+    (define sway_path ".container.app_id")
+    (defun idmatch (name) (== (if (has-key sway_path) (load sway_path) (load i3_path)) name))
+    (defun match (name) (and (== (load ".change") "new") (idmatch name)))
+    (defun match-load (name) (if (match name) (load ".container.id") F))
+  )
+"#;
 
-    let cb_programs = programs.clone();
-    let cb = move |a, b| new_window_cb(a, b, &cb_config, &cb_args, &cb_programs, &tx);
-    sub_connection
-        .subscribe(&[MessageType::SubWindow], &cb)
-        .await?;
+    fn config(rest: &str) -> Config {
+        let config: Config = serde_yaml::from_str(&format!("{}{}", INIT, rest)).unwrap();
+        config.validate().unwrap();
+        config
+    }
 
-    tokio::join!(
-        timeout(
-            Duration::from_millis(config.timeout),
-            sub_connection.run(&mut rx)
-        ),
-        run(&mut connection, &config),
-    )
-    .1?;
-    {
-        let p = programs.lock().await;
-        if p.len() != 0 {
-            warn!("Not all programs consumed: {:?}", &p);
-            info!("Maybe the timouts are too short?");
+    #[tokio::test]
+    async fn places_windows_and_runs_final_command() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(10, "firefox"))],
+        );
+        server.on_command(
+            "exec thunderbird",
+            vec![window_event("new", i3_window(11, "thunderbird"))],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+cmd: 'workspace 1'
+programs:
+- run: 'exec firefox'
+  cmd: '[con_id="{result}"] move container to workspace 2'
+  match: '(match-load "firefox")'
+- run: 'exec thunderbird'
+  cmd: '[con_id="{result}"] move container to workspace 3'
+  match: '(match-load "thunderbird")'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        let commands = server.commands();
+        assert_eq!(commands.len(), 5);
+        for command in [
+            "exec firefox",
+            "exec thunderbird",
+            r#"[con_id="10"] move container to workspace 2"#,
+            r#"[con_id="11"] move container to workspace 3"#,
+        ] {
+            assert!(commands.contains(&command.to_string()), "{:?}", commands);
         }
+        assert_eq!(commands.last().unwrap(), "workspace 1");
     }
 
-    if let Some(cmd) = &config.cmd {
-        connection
-            .communicate(&MessageType::Command, cmd.as_bytes())
-            .await?;
+    #[tokio::test]
+    async fn burst_of_windows_is_not_lost() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec launcher",
+            vec![
+                window_event("focus", sway_window(20, "other")),
+                window_event("new", sway_window(21, "alpha")),
+                window_event("new", sway_window(22, "beta")),
+                window_event("new", sway_window(23, "gamma")),
+            ],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec launcher'
+  cmd: 'mark alpha {result}'
+  match: '(match-load "alpha")'
+- cmd: 'mark beta {result}'
+  match: '(match-load "beta")'
+- cmd: 'mark gamma {result}'
+  match: '(match-load "gamma")'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        let commands = server.commands();
+        for command in ["mark alpha 21", "mark beta 22", "mark gamma 23"] {
+            assert!(commands.contains(&command.to_string()), "{:?}", commands);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn matchers_query_the_window_manager() {
+        let server = MockServer::start().unwrap();
+        server.set_reply(
+            MessageType::Tree,
+            mock::tree(vec![sway_window(5, "foot"), sway_window(6, "firefox")]),
+        );
+        server.set_reply(
+            MessageType::Outputs,
+            serde_json::json!([{"name": "eDP-1", "active": true}, {"name": "HDMI-A-1", "active": true}]),
+        );
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(6, "firefox"))],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec firefox'
+  cmd: '[con_id="{result}"] focus'
+  match: |
+    (if (and (match "firefox") (== (load "[-1].name" (i3-outputs)) "HDMI-A-1"))
+      (load ".id" (find-node (lambda (n) (== (load-or ".app_id" F n) "foot"))))
+      F)
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert!(
+            server
+                .commands()
+                .contains(&r#"[con_id="5"] focus"#.to_string()),
+            "{:?}",
+            server.commands()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn matchers_see_the_typed_state() {
+        let server = MockServer::start().unwrap();
+        server.set_reply(MessageType::Marks, serde_json::json!(["web"]));
+        server.set_reply(
+            MessageType::GetSeats,
+            serde_json::json!([{"name": "seat0", "capabilities": 3, "focus": 7, "extra": "kept"}]),
+        );
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(10, "firefox"))],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec firefox'
+  cmd: 'mark {mode}-{mark}-{extra}'
+  match: |
+    (if (match "firefox")
+      (hash
+        "mode" (load ".name" (i3-binding-state))
+        "mark" (load "[0]" (i3-marks))
+        "extra" (load "[0].extra" (i3-seats)))
+      F)
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(
+            server.commands(),
+            vec!["exec firefox", "mark default-web-kept"]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn prelude_finds_the_workspace_of_windows() {
+        let server = MockServer::start().unwrap();
+        let mut floating = sway_window(8, "mpv");
+        floating["floating_nodes"] = serde_json::json!([sway_window(9, "firefox")]);
+        server.set_reply(
+            MessageType::Tree,
+            mock::tree(vec![sway_window(5, "foot"), floating]),
+        );
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(9, "firefox"))],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec firefox'
+  cmd: 'workspace {result}'
+  match: '(if (and (is-new) (== (app) "firefox")) (workspace-of (con-id)) F)'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(server.commands(), vec!["exec firefox", "workspace 1"]);
+    }
+
+    #[tokio::test]
+    async fn matchers_remember_earlier_windows() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec slack",
+            vec![
+                window_event("new", sway_window(30, "Slack")),
+                window_event("new", sway_window(31, "Slack")),
+            ],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec slack'
+  cmd: 'mark slack {result}'
+  match: |
+    (if (match "Slack")
+      (if (self-get "splash") (load ".container.id") (begin (self-set "splash" T) F))
+      F)
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(server.commands(), vec!["exec slack", "mark slack 31"]);
+    }
+
+    #[tokio::test]
+    async fn criteria_match_without_lisp() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec librewolf",
+            vec![
+                window_event("focus", i3_window(40, "LibreWolf")),
+                window_event("new", i3_window(41, "LibreWolf")),
+                window_event("new", sway_window(42, "foot")),
+            ],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec librewolf'
+  cmd: '[con_id="{result}"] move container to workspace 1'
+  criteria: '[class="^Libre"]'
+- cmd: '[con_id="{result}"] focus'
+  criteria: '[app_id="foot"]'
+  change: new
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(
+            server.commands(),
+            vec![
+                "exec librewolf",
+                r#"[con_id="41"] move container to workspace 1"#,
+                r#"[con_id="42"] focus"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn programs_run_multiple_commands() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec apps",
+            vec![
+                window_event("new", sway_window(50, "foot")),
+                window_event("new", sway_window(51, "firefox")),
+                window_event("new", sway_window(52, "thunderbird")),
+                window_event("new", sway_window(53, "slack")),
+            ],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+strict: true
+programs:
+- run: 'exec apps'
+  match: |
+    (if (match "foot")
+      (list "mark term" "move container to workspace 2" "focus")
+      F)
+- match: |
+    (if (match "firefox")
+      (hash "cmd" (list "[con_id={id}] mark {mark}" "[con_id={id}] focus") "id" (load ".container.id") "mark" "web")
+      F)
+- match: '(match-load "thunderbird")'
+  cmd:
+    eval: |
+      (list
+        (if (> result 51) "floating enable" "floating disable")
+        "resize set 800 600")
+- match: '(match-load "slack")'
+  cmd:
+    eval: '(list "focus" 1)'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        let e = execute(&connection, None, &config).await.unwrap_err();
+
+        assert_eq!(
+            server.commands(),
+            vec![
+                "exec apps",
+                "mark term",
+                "move container to workspace 2",
+                "focus",
+                "[con_id=51] mark web",
+                "[con_id=51] focus",
+                "floating enable",
+                "resize set 800 600",
+            ]
+        );
+        assert!(
+            e.to_string()
+                .contains("program 3: could not evaluate cmd: expected a command, got 1"),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn explicit_cmd_is_never_replaced_by_the_result() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec apps",
+            vec![
+                window_event("new", sway_window(50, "foot")),
+                window_event("new", sway_window(51, "firefox")),
+            ],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec apps'
+  cmd: 'mark {result}'
+  match: '(if (match "foot") (list "exec evil" "kill") F)'
+- cmd: '[con_id={id}] mark {mark}'
+  match: |
+    (if (match "firefox")
+      (hash "cmd" "exec evil" "id" (load ".container.id") "mark" "web")
+      F)
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        let commands = server.commands();
+        assert_eq!(commands.len(), 3, "{:?}", commands);
+        assert!(commands[1].starts_with("mark "), "{:?}", commands);
+        assert_eq!(commands[2], "[con_id=51] mark web");
+    }
+
+    #[tokio::test]
+    async fn pointer_sized_ids_are_formatted_exactly() {
+        let server = MockServer::start().unwrap();
+        let id = 94_558_734_861_344;
+        server.on_command(
+            "exec librewolf",
+            vec![
+                window_event("new", i3_window(id, "LibreWolf")),
+                window_event("new", i3_window(id + 8, "thunderbird")),
+            ],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec librewolf'
+  cmd: '[con_id="{result}"] focus'
+  match: '(match-load "LibreWolf")'
+- cmd: '[con_id="{result}"] focus'
+  criteria: '[class="thunderbird"]'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(
+            server.commands(),
+            vec![
+                "exec librewolf".to_string(),
+                format!(r#"[con_id="{}"] focus"#, id),
+                format!(r#"[con_id="{}"] focus"#, id + 8),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn final_command_runs_after_timeout() {
+        let server = MockServer::start().unwrap();
+        let config = config(
+            r#"
+timeout: 100
+cmd: 'workspace 1'
+programs:
+- run: 'exec never'
+  cmd: 'mark never'
+  match: '(match-load "never")'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(server.commands(), vec!["exec never", "workspace 1"]);
+    }
+
+    #[tokio::test]
+    async fn timeout_does_not_interrupt_commands() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(10, "firefox"))],
+        );
+        server.delay_command("mark slow", Duration::from_millis(300));
+        let config = config(
+            r#"
+timeout: 100
+strict: true
+cmd: 'workspace 1'
+programs:
+- run: 'exec firefox'
+  cmd: 'mark slow {result}'
+  match: '(match-load "firefox")'
+- cmd: 'mark never'
+  match: '(match-load "never")'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(
+            server.commands(),
+            vec!["exec firefox", "mark slow 10", "workspace 1"]
+        );
+        assert_eq!(server.ticks().last().unwrap(), "i3toolwait:done");
+    }
+
+    #[tokio::test]
+    async fn commands_survive_a_restart() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(10, "firefox"))],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+cmd: 'workspace 1'
+programs:
+- run: 'exec firefox'
+  cmd: 'mark {result}'
+  match: '(match-load "firefox")'
+- run: 'exec helper'
+  timeout: 300
+- run: 'exec after'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        // Every program matched already, the restart happens while waiting
+        // for the signal.
+        let (executed, _) =
+            tokio::join!(execute(&connection, Some(server.path()), &config), async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                server.restart().await;
+            });
+
+        executed.unwrap();
+        let commands = server.commands();
+        for command in ["exec helper", "exec after", "workspace 1"] {
+            assert!(commands.contains(&command.to_string()), "{:?}", commands);
+        }
+        assert_eq!(server.ticks().last().unwrap(), "i3toolwait:done");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queries_survive_a_restart() {
+        let server = MockServer::start().unwrap();
+        server.set_reply(
+            MessageType::Tree,
+            mock::tree(vec![sway_window(6, "firefox")]),
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- cmd: 'workspace {result}'
+  match: '(if (and (is-new) (== (app) "firefox")) (workspace-of (con-id)) F)'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        let (executed, _) =
+            tokio::join!(execute(&connection, Some(server.path()), &config), async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                server.restart().await;
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let (message_type, payload) = window_event("new", sway_window(6, "firefox"));
+                server.send_event(message_type, payload);
+            });
+
+        executed.unwrap();
+        assert_eq!(server.commands(), vec!["workspace 1"]);
+    }
+
+    #[tokio::test]
+    async fn progress_ticks_are_optional() {
+        let server = MockServer::start().unwrap();
+        server.ignore_requests(MessageType::Tick);
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(10, "firefox"))],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+strict: true
+cmd: 'workspace 1'
+programs:
+- run: 'exec firefox'
+  cmd: 'mark {result}'
+  match: '(match-load "firefox")'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(
+            server.commands(),
+            vec!["exec firefox", "mark 10", "workspace 1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn tick_entries_wait_for_their_payload() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec helper",
+            vec![(
+                MessageType::SubTick,
+                serde_json::json!({"first": false, "payload": "helper:ready"}),
+            )],
+        );
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(10, "firefox"))],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+programs:
+- run: 'exec helper'
+  tick: 'helper:ready'
+  timeout: 1500
+- run: 'exec firefox'
+  name: browser
+  cmd: 'mark {result}'
+  match: '(match-load "firefox")'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config).await.unwrap();
+
+        assert_eq!(
+            server.commands(),
+            vec!["exec helper", "exec firefox", "mark 10"]
+        );
+        assert_eq!(
+            server.ticks(),
+            vec![
+                "i3toolwait:started",
+                "i3toolwait:matched:browser",
+                "i3toolwait:done"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn backlog_limits_apply_to_ticks() {
+        let server = MockServer::start().unwrap();
+        let tick = |payload: &str| {
+            (
+                MessageType::SubTick,
+                serde_json::json!({"first": false, "payload": payload}),
+            )
+        };
+        server.on_command(
+            "exec helper",
+            vec![tick("noise"), tick("noise"), tick("noise"), tick("ready")],
+        );
+        let programs = r#"
+timeout: 100
+programs:
+- run: 'exec helper'
+  timeout: 100
+- tick: 'ready'
+  timeout: 1000
+"#;
+        let connection = Connection::connect(server.path()).unwrap();
+
+        // The first tick, i3toolwait:started and one noise tick fill the backlog.
+        for (policy, waits) in [("drop", true), ("coalesce", false)] {
+            let config = config(&format!(
+                "backlog:\n  capacity: 3\n  policy: {}{}",
+                policy, programs
+            ));
+            let start = Instant::now();
+            execute(&connection, None, &config).await.unwrap();
+            assert_eq!(
+                start.elapsed() >= Duration::from_millis(1000),
+                waits,
+                "{}",
+                policy
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_commands_only_fail_strict_runs() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(10, "firefox"))],
+        );
+        server.fail_command("move container");
+        let programs = r#"
+timeout: 2000
+programs:
+- run: 'exec firefox'
+  cmd: '[con_id="{result}"] focus; move container to workspace 2'
+  match: '(match-load "firefox")'
+- run: 'move container to workspace 3'
+  timeout: 10
+"#;
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config(programs)).await.unwrap();
+        let e = execute(
+            &connection,
+            None,
+            &config(&format!("strict: true{}", programs)),
+        )
+        .await
+        .unwrap_err();
+        assert!(e.to_string().contains("program 0"), "{}", e);
+        assert!(e.to_string().contains("Failed: move container"), "{}", e);
+        assert!(
+            e.to_string()
+                .contains("program 1: move container to workspace 3 failed"),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn runaway_matchers_fail_their_program() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec firefox",
+            vec![window_event("new", sway_window(10, "firefox"))],
+        );
+        let programs = r#"
+timeout: 2000
+budget:
+  depth: 20
+programs:
+- name: loop
+  cmd: 'mark {result}'
+  match: '(begin (defun forever () (forever)) (forever))'
+- run: 'exec firefox'
+  cmd: '[con_id="{result}"] focus'
+  match: '(match-load "firefox")'
+"#;
+        let connection = Connection::connect(server.path()).unwrap();
+
+        execute(&connection, None, &config(programs)).await.unwrap();
+        assert_eq!(
+            server.commands(),
+            vec!["exec firefox", r#"[con_id="10"] focus"#]
+        );
+        let e = execute(
+            &connection,
+            None,
+            &config(&format!("strict: true{}", programs)),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            e.to_string().contains(
+                "program loop: could not evaluate match: Evaluation exceeded a depth of 20"
+            ),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn window_manager_exit_is_an_error() {
+        let server = MockServer::start().unwrap();
+        server.on_command(
+            "exec firefox",
+            vec![(
+                MessageType::SubShutdown,
+                serde_json::json!({"change": "exit"}),
+            )],
+        );
+        let config = config(
+            r#"
+timeout: 2000
+cmd: 'workspace 1'
+programs:
+- run: 'exec firefox'
+  cmd: 'mark firefox'
+  match: '(match-load "firefox")'
+"#,
+        );
+        let connection = Connection::connect(server.path()).unwrap();
+
+        assert!(execute(&connection, None, &config).await.is_err());
+        assert_eq!(server.commands(), vec!["exec firefox"]);
+    }
+
+    #[test]
+    fn repl_needs_stdin_for_itself() {
+        let mut args = Args::parse_from(["i3toolwait", "-c", "-", "repl"]);
+        let e = args.finish().unwrap_err();
+        assert!(
+            e.to_string().contains("reads expressions from stdin"),
+            "{}",
+            e
+        );
+
+        let mut args = Args::parse_from(["i3toolwait", "-c", "-"]);
+        args.finish().unwrap();
     }
-    Ok(())
 }
@@ -0,0 +1,374 @@
+//! A fake i3 IPC server, so the whole program can be tested without a
+//! running window manager.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::json;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::Duration;
+
+use crate::i3ipc::{read_message, write_message, Message, MessageType};
+
+static SOCKET_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+struct Client {
+    id: usize,
+    subscriptions: Vec<String>,
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+struct Script {
+    pattern: String,
+    events: Vec<(MessageType, serde_json::Value)>,
+}
+
+#[derive(Default)]
+struct State {
+    commands: Vec<String>,
+    ticks: Vec<String>,
+    replies: HashMap<MessageType, serde_json::Value>,
+    scripts: Vec<Script>,
+    failing: Vec<String>,
+    delays: Vec<(String, Duration)>,
+    ignored: Vec<MessageType>,
+    clients: Vec<Client>,
+    next_client: usize,
+    /// Tasks serving the open connections.
+    connections: Vec<AbortHandle>,
+}
+
+impl State {
+    /// Queue an event for every client subscribed to it.
+    fn emit(&self, message_type: MessageType, payload: &serde_json::Value) {
+        let name = message_type.event_name().unwrap();
+        for client in self.clients.iter() {
+            if client.subscriptions.iter().any(|s| s == name) {
+                let _ = client
+                    .sender
+                    .send((message_type, payload.to_string().into_bytes()));
+            }
+        }
+    }
+}
+
+pub struct MockServer {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Listen on a fresh socket in the temporary directory.
+    pub fn start() -> Result<Self, anyhow::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "i3toolwait-mock-{}-{}.sock",
+            std::process::id(),
+            SOCKET_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+
+        let mut state = State::default();
+        state.replies.insert(
+            MessageType::Version,
+            json!({
+                "major": 4,
+                "minor": 23,
+                "patch": 0,
+                "human_readable": "4.23 (mock)",
+                "loaded_config_file_name": "/dev/null",
+            }),
+        );
+        state.replies.insert(MessageType::Tree, tree(vec![]));
+        state.replies.insert(MessageType::Workspace, json!([]));
+        state.replies.insert(MessageType::Outputs, json!([]));
+        state.replies.insert(MessageType::Marks, json!([]));
+        state.replies.insert(MessageType::GetInputs, json!([]));
+        state.replies.insert(MessageType::GetSeats, json!([]));
+        state
+            .replies
+            .insert(MessageType::BindingState, json!({"name": "default"}));
+        let state = Arc::new(Mutex::new(state));
+
+        let task_state = state.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let connection = tokio::spawn(serve(stream, task_state.clone()));
+                task_state
+                    .lock()
+                    .unwrap()
+                    .connections
+                    .push(connection.abort_handle());
+            }
+        });
+        Ok(Self { path, state, task })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All commands received so far, in order.
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// Payloads of all ticks received so far, in order.
+    pub fn ticks(&self) -> Vec<String> {
+        self.state.lock().unwrap().ticks.clone()
+    }
+
+    /// Reply with `reply` to every request of type `message_type`.
+    pub fn set_reply(&self, message_type: MessageType, reply: serde_json::Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .insert(message_type, reply);
+    }
+
+    /// Emit `events` whenever a command containing `pattern` is received.
+    ///
+    /// The events are sent before the reply to the command, like a fast
+    /// window manager would.
+    pub fn on_command(&self, pattern: &str, events: Vec<(MessageType, serde_json::Value)>) {
+        self.state.lock().unwrap().scripts.push(Script {
+            pattern: pattern.to_string(),
+            events,
+        });
+    }
+
+    /// Report commands containing `pattern` as failed.
+    pub fn fail_command(&self, pattern: &str) {
+        self.state.lock().unwrap().failing.push(pattern.to_string());
+    }
+
+    /// Never reply to requests of `message_type`.
+    pub fn ignore_requests(&self, message_type: MessageType) {
+        self.state.lock().unwrap().ignored.push(message_type);
+    }
+
+    /// Wait for `delay` before replying to commands or ticks containing
+    /// `pattern`.
+    pub fn delay_command(&self, pattern: &str, delay: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .delays
+            .push((pattern.to_string(), delay));
+    }
+
+    /// Announce a restart like i3 does and close every connection, new
+    /// connections are accepted as before.
+    pub async fn restart(&self) {
+        // Clients may connect again before the old connections are closed.
+        let (connections, clients) = {
+            let mut state = self.state.lock().unwrap();
+            state.emit(MessageType::SubShutdown, &json!({"change": "restart"}));
+            let clients: Vec<usize> = state.clients.iter().map(|c| c.id).collect();
+            (std::mem::take(&mut state.connections), clients)
+        };
+        // Give the event time to be written before the connections close.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for connection in connections {
+            connection.abort();
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .clients
+            .retain(|c| !clients.contains(&c.id));
+    }
+
+    /// Send an event to every client subscribed to it.
+    pub fn send_event(&self, message_type: MessageType, payload: serde_json::Value) {
+        self.state.lock().unwrap().emit(message_type, &payload);
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+async fn serve(stream: UnixStream, state: Arc<Mutex<State>>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Message>();
+    let id = {
+        let mut state = state.lock().unwrap();
+        let id = state.next_client;
+        state.next_client += 1;
+        state.clients.push(Client {
+            id,
+            subscriptions: vec![],
+            sender: sender.clone(),
+        });
+        id
+    };
+    let writer_task = tokio::spawn(async move {
+        while let Some((message_type, payload)) = receiver.recv().await {
+            if write_message(&mut writer, &message_type, &payload)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    state
+        .lock()
+        .unwrap()
+        .connections
+        .push(writer_task.abort_handle());
+
+    while let Ok((message_type, payload)) = read_message(&mut reader).await {
+        let (reply, after, delay) = {
+            let mut state = state.lock().unwrap();
+            if state.ignored.contains(&message_type) {
+                continue;
+            }
+            let mut after = Vec::new();
+            let mut delay = None;
+            let reply = match message_type {
+                MessageType::Command => {
+                    let command = String::from_utf8_lossy(&payload).to_string();
+                    state.commands.push(command.clone());
+                    delay = state
+                        .delays
+                        .iter()
+                        .find(|(p, _)| command.contains(p.as_str()))
+                        .map(|(_, d)| *d);
+                    for script in state.scripts.iter() {
+                        if command.contains(&script.pattern) {
+                            for (message_type, event) in script.events.iter() {
+                                state.emit(*message_type, event);
+                            }
+                        }
+                    }
+                    let outcomes: Vec<serde_json::Value> = command
+                        .split(';')
+                        .map(|c| {
+                            if state.failing.iter().any(|p| c.contains(p.as_str())) {
+                                json!({"success": false, "error": format!("Failed: {}", c.trim())})
+                            } else {
+                                json!({"success": true})
+                            }
+                        })
+                        .collect();
+                    json!(outcomes)
+                }
+                MessageType::Subscribe => {
+                    let events: Vec<String> = serde_json::from_slice(&payload).unwrap_or_default();
+                    if events.iter().any(|e| e == "tick") {
+                        let first = json!({"first": true, "payload": ""}).to_string();
+                        after.push((MessageType::SubTick, first.into_bytes()));
+                    }
+                    if let Some(client) = state.clients.iter_mut().find(|c| c.id == id) {
+                        client.subscriptions.extend(events);
+                    }
+                    json!({"success": true})
+                }
+                MessageType::Tick => {
+                    let payload = String::from_utf8_lossy(&payload).to_string();
+                    state.ticks.push(payload.clone());
+                    delay = state
+                        .delays
+                        .iter()
+                        .find(|(p, _)| payload.contains(p.as_str()))
+                        .map(|(_, d)| *d);
+                    state.emit(
+                        MessageType::SubTick,
+                        &json!({"first": false, "payload": payload}),
+                    );
+                    json!({"success": true})
+                }
+                _ => state
+                    .replies
+                    .get(&message_type)
+                    .cloned()
+                    .unwrap_or_else(|| json!({"success": true})),
+            };
+            (reply, after, delay)
+        };
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        let _ = sender.send((message_type, reply.to_string().into_bytes()));
+        for message in after {
+            let _ = sender.send(message);
+        }
+    }
+
+    state.lock().unwrap().clients.retain(|c| c.id != id);
+    writer_task.abort();
+}
+
+/// A layout tree with `windows` on a single workspace.
+pub fn tree(windows: Vec<serde_json::Value>) -> serde_json::Value {
+    json!({
+        "id": 1,
+        "name": "root",
+        "type": "root",
+        "nodes": [{
+            "id": 2,
+            "name": "eDP-1",
+            "type": "output",
+            "nodes": [{
+                "id": 3,
+                "name": "1",
+                "type": "workspace",
+                "num": 1,
+                "nodes": windows,
+            }],
+        }],
+    })
+}
+
+/// A window container as sway reports it.
+pub fn sway_window(id: i64, app_id: &str) -> serde_json::Value {
+    json!({
+        "id": id,
+        "name": app_id,
+        "type": "con",
+        "app_id": app_id,
+        "pid": 1000 + id,
+        "shell": "xdg_shell",
+        "nodes": [],
+        "floating_nodes": [],
+    })
+}
+
+/// A window container as i3 reports it.
+pub fn i3_window(id: i64, class: &str) -> serde_json::Value {
+    json!({
+        "id": id,
+        "name": class,
+        "type": "con",
+        "window": 0x400000 + id,
+        "window_properties": {
+            "class": class,
+            "instance": class.to_lowercase(),
+            "title": class,
+        },
+        "nodes": [],
+        "floating_nodes": [],
+    })
+}
+
+/// A window event for `container`.
+pub fn window_event(
+    change: &str,
+    container: serde_json::Value,
+) -> (MessageType, serde_json::Value) {
+    (
+        MessageType::SubWindow,
+        json!({"change": change, "container": container}),
+    )
+}
@@ -0,0 +1,26 @@
+; Evaluated before init, see the README for a description of each function.
+
+; The app_id of Wayland windows, the class of X11 windows.
+(defun app ()
+  (if (has-key ".container.app_id")
+    (load ".container.app_id")
+    (load-or ".container.window_properties.class" F)))
+
+(defun is-new () (== (load ".change") "new"))
+
+(defun con-id () (load ".container.id"))
+
+(defun title ()
+  (load-or ".container.window_properties.title" (load-or ".container.name" F)))
+
+(defun __contains-con__ (node id)
+  (or (== (load-or ".id" F node) id)
+      (any (lambda (n) (__contains-con__ n id)) (load-or ".nodes" (list) node))
+      (any (lambda (n) (__contains-con__ n id)) (load-or ".floating_nodes" (list) node))))
+
+; The name of the workspace containing the container with the given id.
+(defun workspace-of (id)
+  (load-or ".name"
+    F
+    (find-node (lambda (n)
+      (and (== (load-or ".type" F n) "workspace") (__contains-con__ n id))))))
@@ -0,0 +1,247 @@
+//! Evaluate lisp expressions against live window events, to try out
+//! matchers without restarting the session.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use futures::StreamExt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::{self, Config};
+use crate::i3ipc::{Connection, Event, MessageType, ShutdownChange, ShutdownEvent, WindowEvent};
+use crate::lisp;
+
+/// Number of window events kept to choose from.
+const HISTORY: usize = 20;
+
+const HELP: &str = "\
+Expressions are evaluated against the selected window event.
+:events  list the recent window events
+:use N   select event N, without N the newest event is used
+:show    print the selected event
+:help    print this help
+:quit    exit
+";
+
+/// One line describing a window event.
+fn summarize(id: usize, event: &WindowEvent) -> String {
+    let container = &event.container;
+    let app = container.app_id.as_deref().or_else(|| {
+        container
+            .window_properties
+            .as_ref()
+            .and_then(|p| p.class.as_deref())
+    });
+    format!(
+        "[{}] {:?}: {} {:?} (con_id {})",
+        id,
+        event.change,
+        app.unwrap_or("-"),
+        container.name.as_deref().unwrap_or_default(),
+        container.id
+    )
+}
+
+struct History {
+    events: VecDeque<(usize, serde_json::Value)>,
+    next: usize,
+    /// The event chosen with `:use`, otherwise the newest one is used.
+    selected: Option<usize>,
+}
+
+impl History {
+    fn current(&self) -> Option<&(usize, serde_json::Value)> {
+        match self.selected {
+            Some(id) => self.events.iter().find(|(i, _)| *i == id),
+            None => self.events.back(),
+        }
+    }
+}
+
+/// The reply to `line`, or `None` to quit.
+fn respond(line: &str, history: &mut History, interpreter: &lisp::Interpreter) -> Option<String> {
+    let mut words = line.split_whitespace();
+    let reply = match words.next() {
+        None => String::new(),
+        Some(":help") => HELP.to_string(),
+        Some(":events") => history
+            .events
+            .iter()
+            .map(|(id, event)| match serde_json::from_value(event.clone()) {
+                Ok(event) => format!("{}\n", summarize(*id, &event)),
+                Err(_) => format!("[{}]\n", id),
+            })
+            .collect(),
+        Some(":use") => match words.next().map(str::parse::<usize>) {
+            None => {
+                history.selected = None;
+                "Using the newest event\n".to_string()
+            }
+            Some(Ok(id)) if history.events.iter().any(|(i, _)| *i == id) => {
+                history.selected = Some(id);
+                format!("Using event {}\n", id)
+            }
+            Some(_) => "No such event, see :events\n".to_string(),
+        },
+        Some(":show") => match history.current() {
+            Some((_, event)) => format!("{:#}\n", event),
+            None => "No window event yet\n".to_string(),
+        },
+        Some(":quit") => return None,
+        Some(_) => {
+            let program = match config::parse(line) {
+                Ok(program) => program,
+                Err(e) => return Some(format!("Syntax error: {}\n", e)),
+            };
+            if let Some((_, event)) = history.current() {
+                interpreter.set_input(event);
+            }
+            match interpreter.eval(program) {
+                Ok(v) => format!("{}\n", v),
+                Err(e) => format!("Error: {}\n", e),
+            }
+        }
+    };
+    Some(reply)
+}
+
+/// Read expressions from `input` and write their results, together with
+/// every window event, to `output`.
+pub async fn repl<R, W>(
+    connection: &Connection,
+    socket: Option<&Path>,
+    config: &Config,
+    input: R,
+    mut output: W,
+) -> Result<(), anyhow::Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let context = lisp::Context::with_connection(Arc::new(connection.duplicate()?))
+        .in_directory(config.directory.clone());
+    let interpreter = lisp::Interpreter::new(context, config.budget, config.init.clone().into())
+        .map_err(|e| anyhow!("Failed to evaluate init: {}", e))?;
+    let mut events = connection
+        .subscribe(&[MessageType::SubWindow, MessageType::SubShutdown])
+        .await?;
+    let mut lines = input.lines();
+    let mut history = History {
+        events: VecDeque::with_capacity(HISTORY),
+        next: 0,
+        selected: None,
+    };
+
+    output
+        .write_all(b"Waiting for window events, :help lists the commands\n")
+        .await?;
+    output.flush().await?;
+    loop {
+        let reply = tokio::select! {
+            event = events.next() => match event {
+                Some(Event::Window(event)) => {
+                    let id = history.next;
+                    history.next += 1;
+                    if history.events.len() == HISTORY {
+                        history.events.pop_front();
+                    }
+                    history
+                        .events
+                        .push_back((id, serde_json::to_value(&event)?));
+                    format!("{}\n", summarize(id, &event))
+                }
+                Some(Event::Shutdown(ShutdownEvent {
+                    change: ShutdownChange::Restart,
+                })) => {
+                    connection.reconnect(socket).await?;
+                    if let Some(queries) = interpreter.context().connection() {
+                        queries.reconnect(socket).await?;
+                    }
+                    "Window manager restarted\n".to_string()
+                }
+                Some(Event::Shutdown(_)) | None => {
+                    output.write_all(b"Window manager exited\n").await?;
+                    output.flush().await?;
+                    return Ok(());
+                }
+                Some(_) => continue,
+            },
+            line = lines.next_line() => match line? {
+                Some(line) => match respond(&line, &mut history, &interpreter) {
+                    Some(reply) => reply,
+                    None => return Ok(()),
+                },
+                None => return Ok(()),
+            },
+        };
+        output.write_all(reply.as_bytes()).await?;
+        output.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockServer};
+    use tokio::io::{BufReader, DuplexStream, Lines};
+
+    async fn expect(output: &mut Lines<BufReader<DuplexStream>>, line: &str) {
+        assert_eq!(output.next_line().await.unwrap().unwrap(), line);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn evaluates_against_selected_events() {
+        let server = MockServer::start().unwrap();
+        let config: Config = serde_yaml::from_str(
+            r#"
+init: '(defun app () (load ".container.app_id"))'
+"#,
+        )
+        .unwrap();
+        let connection = Connection::connect(server.path()).unwrap();
+        let (mut input, input_rx) = tokio::io::duplex(1024);
+        let (output_tx, output) = tokio::io::duplex(1024);
+        let mut output = BufReader::new(output).lines();
+
+        let session = async {
+            expect(
+                &mut output,
+                "Waiting for window events, :help lists the commands",
+            )
+            .await;
+            let (message_type, event) = mock::window_event("new", mock::sway_window(7, "foot"));
+            server.send_event(message_type, event);
+            expect(&mut output, r#"[0] New: foot "foot" (con_id 7)"#).await;
+            let (message_type, event) =
+                mock::window_event("focus", mock::sway_window(8, "firefox"));
+            server.send_event(message_type, event);
+            expect(&mut output, r#"[1] Focus: firefox "firefox" (con_id 8)"#).await;
+
+            input.write_all(b"(app)\n").await.unwrap();
+            expect(&mut output, r#""firefox""#).await;
+            input.write_all(b":use 0\n(app)\n").await.unwrap();
+            expect(&mut output, "Using event 0").await;
+            expect(&mut output, r#""foot""#).await;
+            input.write_all(b"(app\n").await.unwrap();
+            expect(
+                &mut output,
+                "Syntax error: line 1, column 1: unclosed (: (app",
+            )
+            .await;
+            input.write_all(b":quit now\n").await.unwrap();
+        };
+        let (result, ()) = tokio::join!(
+            repl(
+                &connection,
+                None,
+                &config,
+                BufReader::new(input_rx),
+                output_tx
+            ),
+            session
+        );
+        result.unwrap();
+    }
+}